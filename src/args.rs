@@ -0,0 +1,382 @@
+//! Declarative parsing of command arguments.
+//!
+//! A [`Parser`] lists the arguments a command takes, parsing a message yields the typed
+//! [`Args`] or an error already carrying the usage of the command.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use telegram_types::bot::types::Message;
+use worker::Url;
+
+use crate::i18n::{self, Lang};
+use crate::invocation::message_args;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Text,
+    Int,
+    Url,
+    /// `90s`, `5m`, `1h30m`, `2d`, `1w`, plain numbers are seconds.
+    Duration,
+    Choice(&'static [&'static str]),
+    /// Option without a value, only valid for named arguments.
+    Flag,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    Url(Url),
+    Duration(Duration),
+    Flag,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Position {
+    Required,
+    Optional,
+    Named,
+    /// Everything left in the message, line breaks included.
+    Rest {
+        required: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Param {
+    name: &'static str,
+    kind: Kind,
+    position: Position,
+}
+
+#[derive(Clone, Debug)]
+pub struct Parser {
+    command: &'static str,
+    params: Vec<Param>,
+}
+
+/// Parsed arguments, looked up by name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Args {
+    values: HashMap<&'static str, Value>,
+}
+
+impl Parser {
+    pub fn new(command: &'static str) -> Self {
+        Self {
+            command,
+            params: vec![],
+        }
+    }
+
+    fn param(mut self, name: &'static str, kind: Kind, position: Position) -> Self {
+        self.params.push(Param {
+            name,
+            kind,
+            position,
+        });
+        self
+    }
+
+    pub fn arg(self, name: &'static str, kind: Kind) -> Self {
+        self.param(name, kind, Position::Required)
+    }
+
+    pub fn optional(self, name: &'static str, kind: Kind) -> Self {
+        self.param(name, kind, Position::Optional)
+    }
+
+    /// `--name value` or `--name=value`.
+    pub fn option(self, name: &'static str, kind: Kind) -> Self {
+        self.param(name, kind, Position::Named)
+    }
+
+    pub fn flag(self, name: &'static str) -> Self {
+        self.param(name, Kind::Flag, Position::Named)
+    }
+
+    pub fn rest(self, name: &'static str) -> Self {
+        self.param(name, Kind::Text, Position::Rest { required: true })
+    }
+
+    pub fn optional_rest(self, name: &'static str) -> Self {
+        self.param(name, Kind::Text, Position::Rest { required: false })
+    }
+
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.command);
+        for param in &self.params {
+            let placeholder = match param.kind {
+                Kind::Choice(choices) => choices.join("|"),
+                _ => format!("<{}>", param.name),
+            };
+            let part = match (param.position, param.kind) {
+                (Position::Named, Kind::Flag) => format!("[--{}]", param.name),
+                (Position::Named, Kind::Choice(_)) => format!("[--{} {}]", param.name, placeholder),
+                (Position::Named, kind) => format!("[--{} <{}>]", param.name, kind.name()),
+                (Position::Required, _) => placeholder,
+                (Position::Optional, _) => format!("[{}]", placeholder),
+                (Position::Rest { required: true }, _) => format!("<{}...>", param.name),
+                (Position::Rest { required: false }, _) => format!("[{}...]", param.name),
+            };
+            usage.push(' ');
+            usage.push_str(&part);
+        }
+        usage
+    }
+
+    /// Parse the text or caption of a message, the leading command is skipped.
+    pub fn parse_message(&self, m: &Message, lang: Lang) -> Result<Args, String> {
+        self.parse(&message_args(m).unwrap_or_default(), lang)
+    }
+
+    /// Errors are in `lang`.
+    pub fn parse(&self, text: &str, lang: Lang) -> Result<Args, String> {
+        self.parse_args(text, lang)
+            .map_err(|err| i18n::format(lang, "usage", &[&err, &self.usage()]))
+    }
+
+    fn parse_args(&self, text: &str, lang: Lang) -> Result<Args, String> {
+        let mut args = Args::default();
+        let mut positional = self
+            .params
+            .iter()
+            .filter(|param| !matches!(param.position, Position::Named));
+        let mut options_done = false;
+        let mut rest = text.trim_start();
+        while !rest.is_empty() {
+            if let Some(option) = rest.strip_prefix("--").filter(|_| !options_done) {
+                let (token, after) =
+                    option.split_at(option.find(char::is_whitespace).unwrap_or(option.len()));
+                if token.is_empty() {
+                    options_done = true;
+                    rest = after.trim_start();
+                    continue;
+                }
+                let (name, inline_value) = match token.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (token, None),
+                };
+                let param = self
+                    .params
+                    .iter()
+                    .find(|param| matches!(param.position, Position::Named) && param.name == name)
+                    .ok_or_else(|| i18n::format(lang, "unknown_option", &[name]))?;
+                let (value, after) = match (param.kind, inline_value) {
+                    (Kind::Flag, None) => (Value::Flag, after),
+                    (Kind::Flag, Some(_)) => {
+                        return Err(i18n::format(lang, "option_without_value", &[name]))
+                    }
+                    (kind, Some(value)) => (kind.value(&value, lang)?, after),
+                    (kind, None) => {
+                        let (value, after) = next_token(after.trim_start(), lang)?;
+                        let value = value
+                            .ok_or_else(|| i18n::format(lang, "missing_option_value", &[name]))?;
+                        (kind.value(&value, lang)?, after)
+                    }
+                };
+                args.values.insert(param.name, value);
+                rest = after.trim_start();
+                continue;
+            }
+            let param = positional
+                .next()
+                .ok_or_else(|| i18n::format(lang, "unexpected_argument", &[rest]))?;
+            if let Position::Rest { .. } = param.position {
+                args.values
+                    .insert(param.name, Value::Text(rest.trim_end().to_string()));
+                break;
+            }
+            let (value, after) = next_token(rest, lang)?;
+            if let Some(value) = value {
+                let value = param
+                    .kind
+                    .value(&value, lang)
+                    .map_err(|err| format!("<{}>: {}", param.name, err))?;
+                args.values.insert(param.name, value);
+            }
+            rest = after.trim_start();
+        }
+        for param in positional {
+            if let Position::Required | Position::Rest { required: true } = param.position {
+                return Err(i18n::format(lang, "missing_argument", &[param.name]));
+            }
+        }
+        Ok(args)
+    }
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Text | Kind::Choice(_) | Kind::Flag => "text",
+            Kind::Int => "number",
+            Kind::Url => "url",
+            Kind::Duration => "duration",
+        }
+    }
+
+    fn value(&self, text: &str, lang: Lang) -> Result<Value, String> {
+        match self {
+            Kind::Text => Ok(Value::Text(text.to_string())),
+            Kind::Int => text
+                .parse()
+                .map(Value::Int)
+                .map_err(|_| i18n::format(lang, "not_number", &[text])),
+            Kind::Url => Url::parse(text)
+                .map(Value::Url)
+                .map_err(|_| i18n::format(lang, "not_url", &[text])),
+            Kind::Duration => parse_duration(text)
+                .map(Value::Duration)
+                .ok_or_else(|| i18n::format(lang, "not_duration", &[text])),
+            Kind::Choice(choices) => match choices.contains(&text) {
+                true => Ok(Value::Text(text.to_string())),
+                false => Err(i18n::format(
+                    lang,
+                    "not_choice",
+                    &[text, &choices.join(", ")],
+                )),
+            },
+            Kind::Flag => Ok(Value::Flag),
+        }
+    }
+}
+
+impl Args {
+    pub fn text(&self, name: &str) -> Option<&str> {
+        match self.values.get(name) {
+            Some(Value::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn int(&self, name: &str) -> Option<i64> {
+        match self.values.get(name) {
+            Some(Value::Int(int)) => Some(*int),
+            _ => None,
+        }
+    }
+
+    pub fn url(&self, name: &str) -> Option<&Url> {
+        match self.values.get(name) {
+            Some(Value::Url(url)) => Some(url),
+            _ => None,
+        }
+    }
+
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        match self.values.get(name) {
+            Some(Value::Duration(duration)) => Some(*duration),
+            _ => None,
+        }
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.values.get(name) == Some(&Value::Flag)
+    }
+}
+
+// a bare word or a quoted string, with the text after it
+fn next_token(text: &str, lang: Lang) -> Result<(Option<String>, &str), String> {
+    let Some(quote) = text.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        let token = Some(text[..end].to_string()).filter(|token| !token.is_empty());
+        return Ok((token, &text[end..]));
+    };
+    let mut token = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' if quote == '"' => match chars.next() {
+                Some((_, escaped)) => token.push(escaped),
+                None => break,
+            },
+            c if c == quote => return Ok((Some(token), &text[index + 1..])),
+            c => token.push(c),
+        }
+    }
+    Err(i18n::format(lang, "unclosed_quote", &[&quote.to_string()]))
+}
+
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    if let Ok(seconds) = text.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let mut seconds = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        seconds = number
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(unit)
+            .and_then(|part| part.checked_add(seconds))?;
+        number.clear();
+    }
+    match number.is_empty() && !text.is_empty() {
+        true => Some(Duration::from_secs(seconds)),
+        false => None,
+    }
+}
+
+#[test]
+fn test_parse_args() {
+    let parser = Parser::new("remind")
+        .arg("in", Kind::Duration)
+        .option("repeat", Kind::Int)
+        .flag("silent")
+        .rest("text");
+    let args = parser
+        .parse(
+            "1h30m --repeat=2 --silent \"buy\" milk\nand eggs ",
+            Lang::En,
+        )
+        .unwrap();
+    assert_eq!(args.duration("in"), Some(Duration::from_secs(5400)));
+    assert_eq!(args.int("repeat"), Some(2));
+    assert!(args.flag("silent"));
+    assert_eq!(args.text("text"), Some("\"buy\" milk\nand eggs"));
+    assert_eq!(
+        parser.parse("soon", Lang::En).unwrap_err(),
+        "<in>: `soon` is not a duration like 90s, 5m or 1h30m\n\
+         Usage: /remind <in> [--repeat <number>] [--silent] <text...>"
+    );
+    assert!(parser.parse("5m", Lang::En).is_err());
+    assert!(parser.parse("5m --loud text", Lang::En).is_err());
+
+    let parser = Parser::new("fetch")
+        .arg("url", Kind::Url)
+        .optional("label", Kind::Text);
+    let args = parser
+        .parse("https://example.com 'my page'", Lang::En)
+        .unwrap();
+    assert_eq!(
+        args.url("url").map(Url::as_str),
+        Some("https://example.com/")
+    );
+    assert_eq!(args.text("label"), Some("my page"));
+    assert!(parser
+        .parse("https://example.com \"open", Lang::En)
+        .is_err());
+    assert!(parser.parse("https://example.com a b", Lang::En).is_err());
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration("1d2h"), Some(Duration::from_secs(93600)));
+    assert_eq!(parse_duration("999999999999999w"), None);
+    assert_eq!(parse_duration("18446744073709551615s1s"), None);
+    assert_eq!(parse_duration("5x"), None);
+}
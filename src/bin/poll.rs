@@ -0,0 +1,118 @@
+//! Long-polling runner for local development.
+//!
+//! Runs the bot natively: updates come from `getUpdates` and go through `Bot::handle_update`,
+//! the same dispatch as the webhook of the worker, with the vars of an env file and KV
+//! namespaces kept in JSON files. The replies are sent to the Bot API in order, and the work of
+//! the cron trigger runs once a minute between polls.
+//!
+//! ```bash
+//! cargo run --bin poll [.dev.vars]
+//! ```
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    local::run();
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod local {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    use fdkevin_bot::bot::{Bot, ACCEPTED_TYPES};
+    use fdkevin_bot::platform::{self, Env};
+    use fdkevin_bot::reply::Action;
+    use fdkevin_bot::{bots, run_scheduled, VAR_KV_STORE};
+    use futures::executor::block_on;
+    use serde_json::{json, Value};
+
+    /// Name of the bot to run when `BOTS` lists several, the first one by default.
+    const VAR_BOT: &str = "BOT";
+    /// Directory of the KV namespaces, `.wrangler/local-kv` by default.
+    const VAR_KV_DIR: &str = "KV_DIR";
+    const DEFAULT_KV_DIR: &str = ".wrangler/local-kv";
+    const DEFAULT_KV_STORE: &str = "KV_STORE";
+    const POLL_TIMEOUT: u64 = 30;
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+    fn call(bot: &Bot<'_>, method: &str, payload: Value) -> Result<Value, worker::Error> {
+        let action = Action::Call {
+            method: method.to_string(),
+            payload,
+        };
+        block_on(bot.execute(action))
+    }
+
+    fn handle(bot: &Bot<'_>, env: &Env, update: Value) -> Result<(), worker::Error> {
+        let reply = block_on(bot.handle_update(update, env))?;
+        for action in reply.into_actions() {
+            if let Err(err) = block_on(bot.execute(action)) {
+                eprintln!("Failed to execute reply action: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run() {
+        let env_file = std::env::args().nth(1).unwrap_or(".dev.vars".to_string());
+        if let Err(err) = dotenvy::from_filename(&env_file) {
+            eprintln!("Not loading {}: {}", env_file, err);
+        }
+        let mut vars = std::env::vars().collect::<HashMap<String, String>>();
+        vars.entry(VAR_KV_STORE.to_string())
+            .or_insert(DEFAULT_KV_STORE.to_string());
+        let kv_dir = PathBuf::from(vars.get(VAR_KV_DIR).map_or(DEFAULT_KV_DIR, String::as_str));
+        let name = vars.get(VAR_BOT).cloned();
+        let env = Env::local(vars, Some(kv_dir.clone()));
+
+        let bots = bots(&env).unwrap_or_else(|err| panic!("Failed to set up the bots: {}", err));
+        let bot = match &name {
+            Some(name) => bots.iter().find(|bot| &bot.name == name),
+            None => bots.first(),
+        }
+        .unwrap_or_else(|| panic!("No bot named {:?}", name));
+
+        // getUpdates doesn't work while a webhook is set
+        if let Err(err) = call(bot, "deleteWebhook", json!({})) {
+            eprintln!("Failed to delete webhook: {}", err);
+        }
+        println!("Polling updates, KV data is kept in {}", kv_dir.display());
+        let mut offset = 0;
+        let mut minute = 0;
+        loop {
+            // the cron trigger of the worker runs every minute
+            let now = platform::now_millis() / 60_000;
+            if now != minute {
+                minute = now;
+                block_on(run_scheduled(&env));
+            }
+            let payload = json!({
+                "offset": offset,
+                "timeout": POLL_TIMEOUT,
+                "allowed_updates": ACCEPTED_TYPES,
+            });
+            let updates = match call(bot, "getUpdates", payload) {
+                Ok(Value::Array(updates)) => updates,
+                Ok(_) => vec![],
+                Err(err) => {
+                    eprintln!("Failed to get updates: {}", err);
+                    thread::sleep(RETRY_DELAY);
+                    continue;
+                }
+            };
+            for update in updates {
+                if let Some(update_id) = update["update_id"].as_i64() {
+                    offset = offset.max(update_id + 1);
+                }
+                let update_id = update["update_id"].clone();
+                if let Err(err) = handle(bot, &env, update) {
+                    eprintln!("Failed to handle update {}: {}", update_id, err);
+                }
+            }
+        }
+    }
+}
@@ -1,36 +1,208 @@
-use futures::future::LocalBoxFuture;
+use futures::future::{self, Either, LocalBoxFuture};
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use telegram_types::bot::methods::{
-    ApiError, ChatTarget, DeleteWebhook, GetChat, GetChatMember, GetMe, Method, SetWebhook,
-    TelegramResult, UpdateTypes,
+    ApiError, ChatTarget, DeleteWebhook, GetChat, GetChatMember, GetMe, Method, SendMessage,
+    TelegramResult,
 };
 use telegram_types::bot::types::{
-    Chat, ChatMember, ChatMemberStatus, Message, Update, UpdateContent, User, UserId,
+    CallbackQuery, Chat, ChatId, ChatMember, ChatMemberStatus, File, FileId, Message, MessageId,
+    Update, UpdateContent, User, UserId,
 };
-use worker::kv::KvStore;
-use worker::wasm_bindgen::JsValue;
 use worker::{
-    console_debug, console_log, Env, Error as WorkerError, Fetch, Headers, Method as RequestMethod,
-    Request, RequestInit, Response, RouteContext,
+    Delay, Error as WorkerError, Method as RequestMethod, Request, Response, RouteContext,
 };
 
-use std::borrow::Cow;
 use std::collections::HashMap;
 use std::future::Future;
 use std::rc::Rc;
+use std::time::Duration;
 
-const ACCEPTED_TYPES: &[UpdateTypes] = &[UpdateTypes::Message];
+use crate::args::Parser;
+use crate::captcha::{self, ChatJoinRequest};
+use crate::command::return_message;
+use crate::credits::{self, PreCheckoutQuery, SuccessfulPayment};
+use crate::dialog::{self, Dialog};
+use crate::feedback::{self, MessageReactionUpdated};
+use crate::i18n::{self, Lang};
+use crate::invocation::Invocation;
+use crate::media;
+use crate::methods::{
+    AnswerCallbackQuery, BotCommandScope, ChatPermissions, GetChatPermissions, GetFile,
+    ReactionType, SetMessageReaction, SetWebhook,
+};
+use crate::middleware;
+use crate::moderation;
+use crate::platform::Env;
+use crate::platform::{self, HttpResponse};
+use crate::reply::{Action, Reply};
+use crate::store::{Kv, Store};
+use crate::{console_debug, console_error, console_log};
+
+pub const ACCEPTED_TYPES: &[&str] = &[
+    "message",
+    "message_reaction",
+    "pre_checkout_query",
+    "callback_query",
+    "chat_join_request",
+];
+pub const DEFAULT_API_BASE: &str = "https://api.telegram.org";
+/// Seconds a processed `update_id` is remembered, Telegram gives up redelivering well before.
+const PROCESSED_UPDATE_TTL: u64 = 3600;
+/// Commands suggested for an unknown one.
+const MAX_SUGGESTIONS: usize = 3;
+/// Seconds the username of the bot is cached, it rarely changes.
+const USERNAME_TTL: u64 = 24 * 60 * 60;
+/// How often a chat action is repeated, Telegram shows one for about 5 seconds.
+const CHAT_ACTION_INTERVAL: Duration = Duration::from_secs(4);
+/// Optional var pointing the bot to a local Bot API server or stand-in.
+pub const VAR_API_BASE: &str = "TELEGRAM_API_BASE";
 
 type CommandFn<'a> =
-    Rc<dyn 'a + Fn(Message, Env, Bot<'a>) -> LocalBoxFuture<'a, Result<Response, WorkerError>>>;
+    Rc<dyn 'a + Fn(Message, Env, Bot<'a>) -> LocalBoxFuture<'a, Result<Reply, WorkerError>>>;
+
+/// Category of commands registered without one.
+pub const DEFAULT_CATEGORY: &str = "General";
+
+/// What `/help` and the command menu tell about a command.
+#[derive(Clone, Debug)]
+pub struct CommandInfo {
+    pub description: String,
+    /// Other names the command answers to, e.g. `c` for `chat`.
+    pub aliases: Vec<String>,
+    /// Descriptions by language code, for users of other languages.
+    pub translations: Vec<(String, String)>,
+    pub usage: Option<String>,
+    pub examples: Vec<String>,
+    pub category: String,
+    /// Hidden commands still work, but are left out of `/help` and the command menu.
+    pub hidden: bool,
+    pub scope: BotCommandScope,
+}
+
+impl CommandInfo {
+    pub fn new<S: AsRef<str>>(description: S) -> Self {
+        Self {
+            description: description.as_ref().to_string(),
+            aliases: vec![],
+            translations: vec![],
+            usage: None,
+            examples: vec![],
+            category: DEFAULT_CATEGORY.to_string(),
+            hidden: false,
+            scope: BotCommandScope::Default,
+        }
+    }
+
+    pub fn alias<S: AsRef<str>>(mut self, alias: S) -> Self {
+        self.aliases.push(alias.as_ref().to_ascii_lowercase());
+        self
+    }
+
+    pub fn translation<S: AsRef<str>>(mut self, language_code: S, description: S) -> Self {
+        self.translations.push((
+            language_code.as_ref().to_string(),
+            description.as_ref().to_string(),
+        ));
+        self
+    }
+
+    pub fn description_in(&self, language_code: Option<&str>) -> &str {
+        self.translations
+            .iter()
+            .find(|(code, _)| Some(code.as_str()) == language_code)
+            .map_or(&self.description, |(_, description)| description)
+    }
+
+    /// Usage generated from the arguments the command parses.
+    pub fn args(mut self, parser: Parser) -> Self {
+        self.usage = Some(parser.usage());
+        self
+    }
+
+    pub fn example<S: AsRef<str>>(mut self, example: S) -> Self {
+        self.examples.push(example.as_ref().to_string());
+        self
+    }
+
+    pub fn category<S: AsRef<str>>(mut self, category: S) -> Self {
+        self.category = category.as_ref().to_string();
+        self
+    }
+
+    pub fn hidden(mut self) -> Self {
+        self.hidden = true;
+        self
+    }
+
+    pub fn scope(mut self, scope: BotCommandScope) -> Self {
+        self.scope = scope;
+        self
+    }
+}
+
+#[derive(Clone)]
+pub struct Command<'a> {
+    pub info: CommandInfo,
+    func: CommandFn<'a>,
+}
 
 #[derive(Clone)]
 pub struct Bot<'a> {
+    /// Name the bot is configured under, empty for the unnamed default bot.
+    pub name: String,
     pub token: String,
+    pub api_base: String,
     kv_store: String,
-    pub commands: HashMap<String, CommandFn<'a>>,
+    pub commands: HashMap<String, Command<'a>>,
+    /// Alias to command name.
+    pub aliases: HashMap<String, String>,
     pub default: Option<CommandFn<'a>>,
+    /// Language of the replies to the current update, set by the `locale` middleware.
+    pub lang: Lang,
+    dialogs: HashMap<String, DialogFn<'a>>,
+    middlewares: Vec<MiddlewareFn<'a>>,
+}
+
+type DialogFn<'a> = Rc<
+    dyn 'a + Fn(Message, Env, Bot<'a>, Dialog) -> LocalBoxFuture<'a, Result<Reply, WorkerError>>,
+>;
+
+type MiddlewareFn<'a> = Rc<
+    dyn 'a + Fn(Message, Env, Bot<'a>, Next<'a>) -> LocalBoxFuture<'a, Result<Reply, WorkerError>>,
+>;
+
+/// The rest of the middleware chain, ending with the handler of the message.
+#[derive(Clone)]
+pub struct Next<'a> {
+    middlewares: Rc<Vec<MiddlewareFn<'a>>>,
+    index: usize,
+    handler: Option<CommandFn<'a>>,
+    /// The matched command, `None` for the default handler and unknown commands.
+    pub command: Option<String>,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(
+        mut self,
+        m: Message,
+        env: Env,
+        bot: Bot<'a>,
+    ) -> LocalBoxFuture<'a, Result<Reply, WorkerError>> {
+        match self.middlewares.clone().get(self.index) {
+            Some(middleware) => {
+                self.index += 1;
+                middleware(m, env, bot, self)
+            }
+            None => match self.handler {
+                Some(handler) => handler(m, env, bot),
+                None => {
+                    console_log!("No command matched, ignoring...");
+                    Box::pin(async { Ok(Reply::none()) })
+                }
+            },
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -43,10 +215,16 @@ pub struct WebhookReply<T: Method> {
 impl<'a> Bot<'a> {
     pub fn new<S: AsRef<str>>(token: S, kv_store: S) -> Self {
         Self {
+            name: String::new(),
             token: token.as_ref().to_string(),
+            api_base: DEFAULT_API_BASE.to_string(),
             kv_store: kv_store.as_ref().to_string(),
             commands: HashMap::new(),
+            aliases: HashMap::new(),
             default: None,
+            lang: Lang::default(),
+            dialogs: HashMap::new(),
+            middlewares: vec![],
         }
     }
 
@@ -55,28 +233,27 @@ impl<'a> Bot<'a> {
         method: RequestMethod,
         url: &str,
         payload: &str,
-    ) -> Result<Response, WorkerError> {
-        let mut request_builder = RequestInit::new();
-        let mut headers = Headers::new();
-        if method != RequestMethod::Get {
-            headers.set("Content-Type", "application/json")?;
-            console_log!("Sending JSON payload: {}", payload);
-            request_builder.with_body(Some(JsValue::from_str(payload)));
+    ) -> Result<HttpResponse, WorkerError> {
+        if method == RequestMethod::Get {
+            return platform::fetch(method, url, &[], None).await;
         }
-        request_builder.with_headers(headers).with_method(method);
-        Fetch::Request(Request::new_with_init(url, &request_builder)?)
-            .send()
-            .await
+        console_log!("Sending JSON payload: {}", payload);
+        let headers = [("Content-Type", "application/json")];
+        platform::fetch(method, url, &headers, Some(payload.as_bytes().to_vec())).await
+    }
+
+    fn method_url(&self, method: &str) -> String {
+        format!("{}/bot{}/{}", self.api_base, self.token, method)
     }
 
     pub async fn send_method_request<T: Method>(
         &self,
         request: T,
         method: RequestMethod,
-    ) -> Result<Response, WorkerError> {
+    ) -> Result<HttpResponse, WorkerError> {
         self.send_json_request(
             method,
-            &T::url(&self.token),
+            &self.method_url(T::NAME),
             &serde_json::to_string(&request).map_err(Into::<WorkerError>::into)?,
         )
         .await
@@ -86,37 +263,53 @@ impl<'a> Bot<'a> {
         WorkerError::RustError(e.description)
     }
 
-    pub async fn send_method_get<T: Method>(&self, request: T) -> Result<Response, WorkerError> {
+    pub async fn send_method_get<T: Method>(
+        &self,
+        request: T,
+    ) -> Result<HttpResponse, WorkerError> {
         self.send_method_request(request, RequestMethod::Get).await
     }
 
+    pub async fn call_method<T: Method>(&self, request: T) -> Result<T::Item, WorkerError> {
+        self.send_method_request(request, RequestMethod::Post)
+            .await?
+            .json::<TelegramResult<T::Item>>()?
+            .into_result()
+            .map_err(Bot::convert_error)
+    }
+
     pub async fn get_me(&self) -> Result<User, WorkerError> {
-        let mut result = self.send_method_get(GetMe).await?;
-        result
-            .json::<TelegramResult<User>>()
+        self.send_method_get(GetMe)
             .await?
+            .json::<TelegramResult<User>>()?
             .into_result()
             .map_err(Bot::convert_error)
     }
 
     pub async fn get_chat(&self, chat_id: ChatTarget<'_>) -> Result<Chat, WorkerError> {
-        let mut result = self
-            .send_method_request(GetChat { chat_id }, RequestMethod::Post)
-            .await?;
-        result
-            .json::<TelegramResult<Chat>>()
+        self.send_method_request(GetChat { chat_id }, RequestMethod::Post)
             .await?
+            .json::<TelegramResult<Chat>>()?
             .into_result()
             .map_err(Bot::convert_error)
     }
 
+    /// Permissions members of the group have by default, which restricted members get back.
+    pub async fn default_permissions(&self, chat_id: i64) -> Result<ChatPermissions, WorkerError> {
+        let info = self.call_method(GetChatPermissions { chat_id }).await?;
+        info.permissions.ok_or_else(|| {
+            WorkerError::RustError(format!("Chat {} has no member permissions", chat_id))
+        })
+    }
+
+    pub async fn send_message(&self, message: SendMessage<'_>) -> Result<Message, WorkerError> {
+        self.call_method(message).await
+    }
+
     pub async fn send_chat_action(&self, chat_id: i64, action: &str) -> Result<(), WorkerError> {
         self.send_json_request(
             RequestMethod::Post,
-            &format!(
-                "https://api.telegram.org/bot{}/{}",
-                self.token, "sendChatAction"
-            ),
+            &self.method_url("sendChatAction"),
             &serde_json::to_string(&json!({
                 "chat_id": chat_id,
                 "action": action,
@@ -127,6 +320,73 @@ impl<'a> Bot<'a> {
         Ok(())
     }
 
+    /// Run `work` while showing `action`, e.g. `typing` or `upload_photo`, in the chat.
+    pub async fn with_chat_action<F: Future>(
+        &self,
+        chat_id: i64,
+        action: &str,
+        work: F,
+    ) -> F::Output {
+        if !cfg!(target_arch = "wasm32") {
+            // the local runner has no timers, the action is only sent once
+            if let Err(err) = self.send_chat_action(chat_id, action).await {
+                console_error!("Failed to send chat action {}: {}", action, err);
+            }
+            return work.await;
+        }
+        let ticker = async {
+            loop {
+                if let Err(err) = self.send_chat_action(chat_id, action).await {
+                    console_error!("Failed to send chat action {}: {}", action, err);
+                }
+                Delay::from(CHAT_ACTION_INTERVAL).await;
+            }
+        };
+        futures::pin_mut!(work, ticker);
+        // dropping the ticker when the work is done stops it
+        match future::select(work, ticker).await {
+            Either::Left((output, _)) => output,
+            Either::Right(_) => unreachable!("the ticker never stops"),
+        }
+    }
+
+    /// Set the reaction of the bot on a message, failures are only logged as chats may
+    /// disable reactions.
+    pub async fn react(&self, chat_id: ChatId, message_id: MessageId, emoji: &str) {
+        let reaction = SetMessageReaction {
+            chat_id: ChatTarget::Id(chat_id),
+            message_id,
+            reaction: vec![ReactionType::emoji(emoji)],
+        };
+        if let Err(err) = self.call_method(reaction).await {
+            console_error!("Failed to react with {}: {}", emoji, err);
+        }
+    }
+
+    pub async fn get_file(&self, file_id: &FileId) -> Result<File, WorkerError> {
+        self.call_method(GetFile {
+            file_id: file_id.clone(),
+        })
+        .await
+    }
+
+    pub async fn download_file(&self, file_id: &FileId) -> Result<Vec<u8>, WorkerError> {
+        let file_path = self
+            .get_file(file_id)
+            .await?
+            .file_path
+            .ok_or_else(|| WorkerError::RustError("File is not available".to_string()))?;
+        let url = format!("{}/file/bot{}/{}", self.api_base, self.token, file_path);
+        let resp = platform::fetch(RequestMethod::Get, &url, &[], None).await?;
+        if resp.status != 200 {
+            return Err(WorkerError::RustError(format!(
+                "Failed to download file: {}",
+                resp.status
+            )));
+        }
+        Ok(resp.body)
+    }
+
     pub async fn is_admin(
         &self,
         chat_id: ChatTarget<'_>,
@@ -135,8 +395,7 @@ impl<'a> Bot<'a> {
         let chat_member = self
             .send_method_request(GetChatMember { chat_id, user_id }, RequestMethod::Post)
             .await?
-            .json::<TelegramResult<ChatMember>>()
-            .await?
+            .json::<TelegramResult<ChatMember>>()?
             .into_result()
             .map_err(Bot::convert_error)?;
         let member_status = chat_member.status;
@@ -159,116 +418,478 @@ impl<'a> Bot<'a> {
     //     Ok(())
     // }
 
+    /// Bot configured by vars prefixed with its upper-cased name, e.g. `NAME_TELEGRAM_API_TOKEN`.
+    ///
+    /// Apart from the token, every var falls back to the unprefixed one.
     pub fn new_with_env<S: AsRef<str>>(
         env: &Env,
+        name: S,
         var_token: S,
         var_kv_store: S,
     ) -> Result<Self, WorkerError> {
-        Ok(Self::new(
-            env.secret(var_token.as_ref())?.to_string(),
-            env.var(var_kv_store.as_ref())?.to_string(),
-        ))
+        let mut bot = Self::new("", "");
+        bot.name = name.as_ref().to_string();
+        bot.token = env.secret(&bot.var_name(var_token.as_ref()))?.to_string();
+        bot.kv_store = bot
+            .var(env, var_kv_store.as_ref())
+            .ok_or_else(|| WorkerError::BindingError(var_kv_store.as_ref().to_string()))?;
+        if let Some(api_base) = bot.var(env, VAR_API_BASE) {
+            bot.api_base = api_base.trim_end_matches('/').to_string();
+        }
+        Ok(bot)
+    }
+
+    fn var_name(&self, var: &str) -> String {
+        match self.name.is_empty() {
+            true => var.to_string(),
+            false => format!("{}_{}", self.name.to_ascii_uppercase(), var),
+        }
+    }
+
+    /// Var of this bot, falling back to the one shared by all bots.
+    pub fn var(&self, env: &Env, var: &str) -> Option<String> {
+        env.var(&self.var_name(var))
+            .or_else(|_| env.var(var))
+            .map(|value| value.to_string())
+            .ok()
+    }
+
+    /// Secret of this bot, falling back to the one shared by all bots.
+    pub fn secret(&self, env: &Env, secret: &str) -> Result<String, WorkerError> {
+        env.secret(&self.var_name(secret))
+            .or_else(|_| env.secret(secret))
+            .map(|value| value.to_string())
     }
 
     pub async fn setup_webhook<S: AsRef<str>>(&self, url: S) -> Result<(), WorkerError> {
         let payload = DeleteWebhook;
-        let mut result = self
+        let result = self
             .send_method_request(payload, RequestMethod::Post)
             .await?;
         console_log!(
             "Trying to delete previously set webhooks: {}",
-            result.text().await?
+            result.text()?
         );
-        let mut payload = SetWebhook::new(url.as_ref());
-        payload.allowed_updates = Some(Cow::from(ACCEPTED_TYPES));
-        let mut result = self
+        let payload = SetWebhook {
+            url: url.as_ref().to_string(),
+            allowed_updates: ACCEPTED_TYPES.to_vec(),
+        };
+        let result = self
             .send_method_request(payload, RequestMethod::Post)
             .await?;
-        console_log!("Set new webhook: {}", result.text().await?);
+        console_log!("Set new webhook: {}", result.text()?);
         Ok(())
     }
 
-    pub fn with_default<F: 'a + Future<Output = Result<Response, WorkerError>>>(
+    pub fn with_default<F: 'a + Future<Output = Result<Reply, WorkerError>>>(
         &mut self,
         func: fn(Message, Env, Bot<'a>) -> F,
     ) {
         self.default = Some(Rc::new(move |msg, env, bot| Box::pin(func(msg, env, bot))))
     }
 
-    pub fn register_command<
-        S: AsRef<str>,
-        F: 'a + Future<Output = Result<Response, WorkerError>>,
-    >(
+    /// Handle the messages of users in the dialog `name`, instead of the default handler.
+    pub fn register_dialog<S: AsRef<str>, F: 'a + Future<Output = Result<Reply, WorkerError>>>(
+        &mut self,
+        name: S,
+        func: fn(Message, Env, Bot<'a>, Dialog) -> F,
+    ) {
+        self.dialogs.insert(
+            name.as_ref().to_string(),
+            Rc::new(move |msg, env, bot, dialog| Box::pin(func(msg, env, bot, dialog))),
+        );
+    }
+
+    /// Wrap every handler, middlewares run in the order they are added.
+    pub fn with_middleware<F: 'a + Future<Output = Result<Reply, WorkerError>>>(
+        &mut self,
+        func: fn(Message, Env, Bot<'a>, Next<'a>) -> F,
+    ) {
+        self.middlewares.push(Rc::new(move |msg, env, bot, next| {
+            Box::pin(func(msg, env, bot, next))
+        }))
+    }
+
+    pub fn register_command<S: AsRef<str>, F: 'a + Future<Output = Result<Reply, WorkerError>>>(
         &mut self,
         command: S,
-        // description: S,
         func: fn(Message, Env, Bot<'a>) -> F,
+        info: CommandInfo,
     ) {
+        let name = command.as_ref();
+        debug_assert!(
+            !self.aliases.contains_key(name),
+            "command /{} is already an alias",
+            name
+        );
+        for alias in &info.aliases {
+            debug_assert!(
+                alias != name
+                    && !self.commands.contains_key(alias)
+                    && !self.aliases.contains_key(alias),
+                "alias /{} of /{} is already taken",
+                alias,
+                name
+            );
+            self.aliases.insert(alias.clone(), name.to_string());
+        }
         self.commands.insert(
             command.as_ref().to_string(),
-            Rc::new(move |msg, env, bot| Box::pin(func(msg, env, bot))),
+            Command {
+                info,
+                func: Rc::new(move |msg, env, bot| Box::pin(func(msg, env, bot))),
+            },
         );
     }
 
-    pub async fn run_commands(&self, m: Message, env: Env) -> Result<Response, WorkerError> {
-        let message_text = m.text.clone().unwrap_or_default();
+    /// The command registered under a name or alias, with its name.
+    pub fn resolve<'b>(&'b self, name: &'b str) -> Option<(&'b str, &'b Command<'a>)> {
+        let name = self.aliases.get(name).map_or(name, String::as_str);
+        self.commands.get(name).map(|command| (name, command))
+    }
+
+    /// Visible commands whose name or an alias is close to `name`, closest first.
+    pub fn suggest(&self, name: &str) -> Vec<&str> {
+        let max_distance = (name.chars().count() / 3).max(2);
+        let mut suggestions = self
+            .commands
+            .iter()
+            .filter(|(_, command)| !command.info.hidden)
+            .filter_map(|(command, Command { info, .. })| {
+                let distance = std::iter::once(command)
+                    .chain(&info.aliases)
+                    .map(|candidate| edit_distance(name, candidate))
+                    .min()?;
+                (distance <= max_distance).then_some((distance, command.as_str()))
+            })
+            .collect::<Vec<(usize, &str)>>();
+        suggestions.sort();
+        suggestions
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, command)| command)
+            .collect()
+    }
+
+    /// Registered commands ordered by category, then by name.
+    pub fn sorted_commands(&self) -> Vec<(&str, &CommandInfo)> {
+        let mut commands = self
+            .commands
+            .iter()
+            .map(|(name, command)| (name.as_str(), &command.info))
+            .collect::<Vec<(&str, &CommandInfo)>>();
+        commands.sort_by(|a, b| (&a.1.category, a.0).cmp(&(&b.1.category, b.0)));
+        commands
+    }
+
+    pub async fn run_commands(&self, m: Message, env: Env) -> Result<Reply, WorkerError> {
         console_log!(
-            "Non empty message text from {:?} : {}",
+            "Message from {:?} : {:?}",
             m.chat.clone(),
-            message_text
+            m.text.as_ref().or(m.caption.as_ref())
         );
-        let message_command = message_text.split(' ').collect::<Vec<&str>>()[0]
-            .trim()
-            .to_ascii_lowercase();
-        console_debug!("First phrase extracted from text: {}", message_command);
-        for (command, func) in &self.commands {
-            // `/start bruh` and `/start@blablabot bruh`
-            let command_prefix = format!("/{}", command.to_ascii_lowercase());
-            let command_prefix_extended = format!("{}@THE_BOT_USERNAME", command_prefix,);
-            if (message_command == command_prefix) || (message_command == command_prefix_extended) {
-                console_log!("Command matched: {}", command);
-                return func(m, env, self.clone()).await;
+        let (command, handler) = match Invocation::parse(&m) {
+            Some(invocation) => {
+                console_debug!("Command found: {:?}", invocation);
+                // `/start@other_bot` in a group
+                if invocation.username.is_some()
+                    && !invocation.is_for(self.username(&env).await?.as_deref())
+                {
+                    console_log!("Command for another bot, ignoring...");
+                    return Ok(Reply::none());
+                }
+                match self.resolve(&invocation.command) {
+                    Some((name, command)) => (Some(name.to_string()), Some(command.func.clone())),
+                    // a typo shouldn't end up at the default handler
+                    None => (None, Some(self.unknown_command(&invocation.command))),
+                }
             }
+            // the dialog is loaded after the middlewares, chats that are not allowed cost no read
+            None => (None, Some(self.dialog_or_default())),
+        };
+        Next {
+            middlewares: Rc::new(self.middlewares.clone()),
+            index: 0,
+            handler,
+            command,
         }
-        if let Some(cmd) = &self.default {
-            return cmd(m, env, self.clone()).await;
+        .run(m, env, self.clone())
+        .await
+    }
+
+    /// Handler of messages that aren't commands: the step of the dialog the sender is in, or
+    /// the default handler. A dialog that is no longer registered is dropped.
+    fn dialog_or_default(&self) -> CommandFn<'a> {
+        Rc::new(|m, env, bot| {
+            Box::pin(async move {
+                let handler = match dialog::load(&m, &env, &bot).await? {
+                    Some(dialog) => match bot.dialogs.get(&dialog.name) {
+                        Some(func) => {
+                            console_debug!("Dialog found: {:?}", dialog);
+                            return func(m, env, bot.clone(), dialog).await;
+                        }
+                        None => {
+                            console_log!("Dialog {:?} is not registered, dropping...", dialog.name);
+                            dialog::finish(&m, &env, &bot).await?;
+                            bot.default.clone()
+                        }
+                    },
+                    None => bot.default.clone(),
+                };
+                match handler {
+                    Some(handler) => handler(m, env, bot).await,
+                    None => Ok(Reply::none()),
+                }
+            })
+        })
+    }
+
+    fn unknown_command(&self, command: &str) -> CommandFn<'a> {
+        let command = command.to_string();
+        Rc::new(move |m, _, bot| {
+            let mut text = i18n::format(bot.lang, "unknown_command", &[&command]);
+            let suggestions = bot.suggest(&command);
+            if !suggestions.is_empty() {
+                text += &i18n::format(bot.lang, "did_you_mean", &[&suggestions.join(", /")]);
+            }
+            text += i18n::text(bot.lang, "send_help");
+            let reply = Reply::none().send(
+                SendMessage::new(ChatTarget::Id(m.chat.id), text.as_str()).reply(m.message_id),
+            );
+            Box::pin(async move { Ok(reply) })
+        })
+    }
+
+    pub async fn execute(&self, action: Action) -> Result<Value, WorkerError> {
+        let Action::Call { method, payload } = action;
+        self.send_json_request(
+            RequestMethod::Post,
+            &self.method_url(&method),
+            &serde_json::to_string(&payload).map_err(Into::<WorkerError>::into)?,
+        )
+        .await?
+        .json::<TelegramResult<Value>>()?
+        .into_result()
+        .map_err(Bot::convert_error)
+    }
+
+    /// Run the actions of a reply, the first one becomes the webhook reply if it can.
+    pub async fn respond(&self, reply: Reply) -> Result<Response, WorkerError> {
+        let mut actions = reply.into_actions().into_iter();
+        let webhook_reply = match actions.next() {
+            Some(Action::Call {
+                method,
+                payload: Value::Object(mut content),
+            }) => {
+                content.insert("method".to_string(), Value::String(method));
+                Value::Object(content)
+            }
+            Some(action) => {
+                if let Err(err) = self.execute(action).await {
+                    console_error!("Failed to execute reply action: {}", err);
+                }
+                json!({})
+            }
+            None => json!({}),
+        };
+        for action in actions {
+            if let Err(err) = self.execute(action).await {
+                console_error!("Failed to execute reply action: {}", err);
+            }
         }
-        console_log!("No command matched, ignoring...");
-        Response::empty()
+        Response::from_json(&webhook_reply)
     }
 
     pub async fn process_update(
         req: &mut Request,
         ctx: RouteContext<Bot<'a>>,
     ) -> Result<Response, WorkerError> {
-        let update = req.json::<Update>().await?;
+        let value = req.json::<Value>().await?;
+        let bot = ctx.data;
+        match bot.handle_update(value, &Env::from(&ctx.env)).await {
+            Ok(reply) => bot.respond(reply).await,
+            // Telegram redelivers updates answered with an error
+            Err(err) => {
+                console_error!("Failed to process update: {}", err);
+                Response::error("Failed to process the update", 500)
+            }
+        }
+    }
+
+    /// Handle an update of the webhook or of `getUpdates`, failures of the handlers are logged
+    /// as an error makes Telegram redeliver the update over and over. Payments are the exception:
+    /// a payment that failed to be credited is an error and isn't marked as processed, so it is
+    /// credited when Telegram redelivers it.
+    pub async fn handle_update(&self, value: Value, env: &Env) -> Result<Reply, WorkerError> {
+        let update = serde_json::from_value::<Update>(value.clone())?;
         console_debug!("Received update: {:?}", update);
-        if update.content.is_none() {
-            console_debug!("No content found, ignoring...");
-            return Response::from_json(&json!({}));
+        if let Some(chat_id) = update_chat_id(&value) {
+            if !middleware::is_allowed(self, env, chat_id) && !credits::paid_access(self, env) {
+                console_log!("Chat {} is not allowed, ignoring...", chat_id);
+                return Ok(Reply::none());
+            }
+        }
+        if !self.mark_processed(&update, env).await? {
+            console_log!(
+                "Update {} was processed already, ignoring...",
+                update.update_id.0
+            );
+            return Ok(Reply::none());
+        }
+        let update_id = update.update_id.0;
+        let logged = |result: Result<Reply, WorkerError>| {
+            result.unwrap_or_else(|err| {
+                console_error!("Failed to handle update {}: {}", update_id, err);
+                Reply::none()
+            })
+        };
+        if let Some(reaction) = value.get("message_reaction") {
+            let reaction = serde_json::from_value::<MessageReactionUpdated>(reaction.clone())?;
+            let result = feedback::handle_reaction(reaction, env, self).await;
+            return Ok(logged(result.map(|_| Reply::none())));
+        }
+        if let Some(query) = value.get("pre_checkout_query") {
+            let query = serde_json::from_value::<PreCheckoutQuery>(query.clone())?;
+            return Ok(Reply::call(credits::answer_pre_checkout(&query)));
         }
-        let update_content = update.content.unwrap();
-        if let UpdateContent::Message(m) = update_content {
-            // console_debug!("Got message: {:#?}", m);
-            if m.text.is_none() {
-                console_debug!("No text found, ignoring...");
-                return Response::from_json(&json!({}));
+        if let Some(request) = value.get("chat_join_request") {
+            let request = serde_json::from_value::<ChatJoinRequest>(request.clone())?;
+            let result = captcha::handle_join_request(request, env, self).await;
+            return Ok(logged(result.map(|_| Reply::none())));
+        }
+        match update.content {
+            Some(UpdateContent::Message(m)) => {
+                if let Some(payment) = value["message"].get("successful_payment") {
+                    let payment = serde_json::from_value::<SuccessfulPayment>(payment.clone())?;
+                    return match credits::handle_payment(&m, env, self, payment).await {
+                        Ok(reply) => return_message(&m, reply),
+                        Err(err) => {
+                            self.store(env)?.delete(&processed_key(update_id)).await?;
+                            Err(err)
+                        }
+                    };
+                }
+                if !m.new_chat_members.is_empty() {
+                    let result = captcha::handle_new_members(&m, env, self).await;
+                    return Ok(logged(result.map(|_| Reply::none())));
+                }
+                if m.text.is_none() && m.caption.is_none() && !media::has_attachment(&m) {
+                    console_debug!("No text or attachment found, ignoring...");
+                    return Ok(Reply::none());
+                }
+                Ok(logged(self.run_commands(m, env.clone()).await))
+            }
+            Some(UpdateContent::CallbackQuery(query)) => {
+                Ok(logged(self.handle_callback(&query, env).await))
+            }
+            Some(_) => {
+                console_log!("Not a message, ignoring...");
+                Ok(Reply::none())
             }
-            if m.chat.id.0 != 374506773 {
-                return Response::from_json(&json!({}));
+            None => {
+                console_debug!("No content found, ignoring...");
+                Ok(Reply::none())
             }
-            let bot = ctx.data;
-            let env = ctx.env;
-            bot.run_commands(m, env).await
-        } else {
-            console_log!("Not a message, ignoring...");
-            Response::from_json(&json!({}))
         }
     }
 
-    pub fn get_kv(&self, env: &Env) -> Result<KvStore, WorkerError> {
+    /// Dispatch a press of an inline keyboard button by the prefix of its data.
+    async fn handle_callback(
+        &self,
+        query: &CallbackQuery,
+        env: &Env,
+    ) -> Result<Reply, WorkerError> {
+        let data = query.data.as_deref().unwrap_or_default();
+        match data.split_once(':') {
+            Some((captcha::CALLBACK_PREFIX, data)) => {
+                captcha::handle_callback(query, data, env, self).await
+            }
+            Some((moderation::CALLBACK_PREFIX, data)) => {
+                moderation::handle_callback(query, data, env, self).await
+            }
+            _ => {
+                console_log!("Unknown callback data {:?}, ignoring...", data);
+                Ok(Reply::call(AnswerCallbackQuery::new(&query.id, None)))
+            }
+        }
+    }
+
+    /// Record the update as processed, `false` if it was seen before.
+    ///
+    /// Best effort: KV has no compare-and-set, so two deliveries handled at the same time may
+    /// both pass.
+    async fn mark_processed(&self, update: &Update, env: &Env) -> Result<bool, WorkerError> {
+        let kv = self.store(env)?;
+        let key = processed_key(update.update_id.0);
+        if kv.get(&key).text().await?.is_some() {
+            return Ok(false);
+        }
+        kv.put(&key, "")?
+            .expiration_ttl(PROCESSED_UPDATE_TTL)
+            .execute()
+            .await?;
+        Ok(true)
+    }
+
+    /// Username of the bot, cached in KV after the first `getMe`.
+    pub async fn username(&self, env: &Env) -> Result<Option<String>, WorkerError> {
+        let store = self.store(env)?;
+        if let Some(username) = store.get("BOT_USERNAME").text().await? {
+            return Ok(Some(username));
+        }
+        let username = self.get_me().await?.username;
+        if let Some(username) = &username {
+            store
+                .put("BOT_USERNAME", username.as_str())?
+                .expiration_ttl(USERNAME_TTL)
+                .execute()
+                .await?;
+        }
+        Ok(username)
+    }
+
+    pub fn get_kv(&self, env: &Env) -> Result<Kv, WorkerError> {
         env.kv(&self.kv_store)
     }
+
+    /// KV store with keys namespaced by the bot name.
+    pub fn store(&self, env: &Env) -> Result<Store, WorkerError> {
+        let prefix = match self.name.is_empty() {
+            true => String::new(),
+            false => format!("{}:", self.name),
+        };
+        Ok(Store::new(self.get_kv(env)?, &prefix))
+    }
+}
+
+fn processed_key(update_id: i64) -> String {
+    format!("INDEX_UPDATE:{}", update_id)
+}
+
+/// Chat an update belongs to, `None` for updates of a user like `pre_checkout_query`.
+fn update_chat_id(value: &Value) -> Option<i64> {
+    ["message", "message_reaction", "chat_join_request"]
+        .iter()
+        .find_map(|kind| value.get(kind))
+        .or_else(|| value.get("callback_query")?.get("message"))?
+        .get("chat")?
+        .get("id")?
+        .as_i64()
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
 }
 
 impl<T: Method> From<T> for WebhookReply<T> {
@@ -279,3 +900,21 @@ impl<T: Method> From<T> for WebhookReply<T> {
         }
     }
 }
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("chta", "chat"), 2);
+    assert_eq!(edit_distance("imag", "image"), 1);
+    assert_eq!(edit_distance("", "echo"), 4);
+    assert_eq!(edit_distance("clear", "clear"), 0);
+}
+
+#[test]
+fn test_update_chat_id() {
+    let message = json!({"update_id": 1, "message": {"chat": {"id": -100}}});
+    assert_eq!(update_chat_id(&message), Some(-100));
+    let query = json!({"update_id": 2, "callback_query": {"message": {"chat": {"id": 42}}}});
+    assert_eq!(update_chat_id(&query), Some(42));
+    let checkout = json!({"update_id": 3, "pre_checkout_query": {"from": {"id": 42}}});
+    assert_eq!(update_chat_id(&checkout), None);
+}
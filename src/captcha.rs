@@ -0,0 +1,394 @@
+//! Arithmetic captcha for new members of groups, who stay restricted until they solve it.
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use telegram_types::bot::methods::{ChatTarget, ReplyMarkup, SendMessage};
+use telegram_types::bot::types::{
+    CallbackQuery, Chat, ChatId, ChatType, InlineKeyboardButton, InlineKeyboardButtonPressed,
+    InlineKeyboardMarkup, Message, MessageId, User,
+};
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::i18n::{self, Lang};
+use crate::methods::{
+    AnswerCallbackQuery, ApproveChatJoinRequest, BanChatMember, ChatPermissions,
+    DeclineChatJoinRequest, RestrictChatMember, UnbanChatMember,
+};
+use crate::platform::{self, Env};
+use crate::reminder;
+use crate::reply::Reply;
+use crate::{console_error, console_log};
+
+const KEY_PREFIX: &str = "INDEX_CAPTCHA:";
+/// Due time of the first pending captcha, so the scheduled handler doesn't list them every minute.
+const KEY_NEXT_DUE: &str = "INDEX_CAPTCHA_NEXT_DUE";
+/// Minutes between listings of the captchas even when none is due, in case a concurrent
+/// challenge lost its update of `KEY_NEXT_DUE`.
+const RESCAN_MINUTES: i64 = 10;
+/// Prefix of the callback data of the answer buttons.
+pub const CALLBACK_PREFIX: &str = "captcha";
+/// Seconds new members have to solve the captcha.
+const VAR_CAPTCHA_TIMEOUT: &str = "CAPTCHA_TIMEOUT";
+const DEFAULT_TIMEOUT: i64 = 300;
+const CHOICES: usize = 4;
+
+/// A request to join a group with join requests on, `telegram_types` doesn't know it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChatJoinRequest {
+    pub chat: Chat,
+    pub from: User,
+    /// Private chat with the user, the bot may write to it until the request is handled.
+    pub user_chat_id: i64,
+    pub date: i64,
+}
+
+/// A pending captcha, deleted once answered or expired.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Captcha {
+    pub chat_id: i64,
+    pub user_id: i64,
+    /// Message with the buttons, in the private chat of the user for join requests.
+    pub message_chat_id: i64,
+    pub message_id: i64,
+    pub answer: u8,
+    /// Unix time the member is removed if the captcha isn't solved.
+    pub due: i64,
+    pub join_request: bool,
+}
+
+impl Captcha {
+    // one per member, a member who joins again gets a new captcha instead of another one
+    fn key(&self) -> String {
+        key(self.chat_id, self.user_id)
+    }
+}
+
+fn key(chat_id: i64, user_id: i64) -> String {
+    format!("{}{}:{}", KEY_PREFIX, chat_id, user_id)
+}
+
+/// Two numbers to add and the answers to choose from, picked by `seed`.
+fn challenge(seed: &[u8]) -> (u8, u8, Vec<u8>) {
+    let (a, b) = (seed[0] % 9 + 1, seed[1] % 9 + 1);
+    let mut choices = vec![a + b];
+    // sums are 2 to 18, seeds that repeat themselves fall back to counting up
+    let candidates = seed[2..].iter().map(|byte| byte % 17 + 2).chain(2..=18);
+    for choice in candidates {
+        if choices.len() == CHOICES {
+            break;
+        }
+        if !choices.contains(&choice) {
+            choices.push(choice);
+        }
+    }
+    // the right answer isn't always the first button
+    let position = seed[seed.len() - 1] as usize % choices.len();
+    choices.swap(0, position);
+    (a, b, choices)
+}
+
+// not cryptographic, it only has to differ between members
+fn seed(chat_id: i64, user_id: i64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(format!(
+        "{}:{}:{}",
+        chat_id,
+        user_id,
+        platform::now_millis()
+    ));
+    hasher.finalize().to_vec()
+}
+
+fn keyboard(captcha: &Captcha, choices: &[u8]) -> ReplyMarkup {
+    let buttons = choices
+        .iter()
+        .map(|choice| InlineKeyboardButton {
+            text: choice.to_string(),
+            pressed: InlineKeyboardButtonPressed::CallbackData(format!(
+                "{}:{}:{}:{}",
+                CALLBACK_PREFIX, captcha.chat_id, captcha.user_id, choice
+            )),
+        })
+        .collect();
+    ReplyMarkup::InlineKeyboard(InlineKeyboardMarkup {
+        inline_keyboard: vec![buttons],
+    })
+}
+
+pub async fn is_enabled(env: &Env, bot: &Bot<'_>, chat_id: i64) -> Result<bool, WorkerError> {
+    let get = bot
+        .store(env)?
+        .get(&format!("INDEX_CHAT_CAPTCHA:{}", chat_id));
+    Ok(get.text().await?.as_deref() == Some("on"))
+}
+
+pub async fn set_enabled(
+    env: &Env,
+    bot: &Bot<'_>,
+    chat_id: i64,
+    value: &str,
+) -> Result<(), WorkerError> {
+    bot.store(env)?
+        .put(&format!("INDEX_CHAT_CAPTCHA:{}", chat_id), value)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+fn timeout(env: &Env, bot: &Bot<'_>) -> i64 {
+    bot.var(env, VAR_CAPTCHA_TIMEOUT)
+        .and_then(|timeout| timeout.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+// sends the challenge and remembers it, `text` gets the numbers and the minutes to answer in
+async fn send_challenge(
+    env: &Env,
+    bot: &Bot<'_>,
+    mut captcha: Captcha,
+    text: impl Fn(u8, u8, &str) -> String,
+) -> Result<(), WorkerError> {
+    let timeout = timeout(env, bot);
+    captcha.due = reminder::now() + timeout;
+    let (a, b, choices) = challenge(&seed(captcha.chat_id, captcha.user_id));
+    captcha.answer = a + b;
+    let text = text(a, b, &(timeout / 60).max(1).to_string());
+    let mut message = SendMessage::new(
+        ChatTarget::Id(ChatId(captcha.message_chat_id)),
+        text.as_str(),
+    );
+    message.reply_markup = Some(keyboard(&captcha, &choices));
+    captcha.message_id = bot.send_message(message).await?.message_id.0;
+    let store = bot.store(env)?;
+    store.put(&captcha.key(), &captcha)?.execute().await?;
+    let next_due = store.get(KEY_NEXT_DUE).json::<i64>().await?;
+    if next_due.is_none_or(|next_due| captcha.due < next_due) {
+        store.put(KEY_NEXT_DUE, captcha.due)?.execute().await?;
+    }
+    Ok(())
+}
+
+// restricts a new member and sends them the challenge
+async fn challenge_member(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    lang: Lang,
+    user: &User,
+) -> Result<(), WorkerError> {
+    console_log!("Asking {} to solve a captcha in {}", user.id.0, m.chat.id.0);
+    bot.call_method(RestrictChatMember {
+        chat_id: m.chat.id.0,
+        user_id: user.id.0,
+        permissions: ChatPermissions::all(false),
+        until_date: None,
+    })
+    .await?;
+    let captcha = Captcha {
+        chat_id: m.chat.id.0,
+        user_id: user.id.0,
+        message_chat_id: m.chat.id.0,
+        message_id: 0,
+        answer: 0,
+        due: 0,
+        join_request: false,
+    };
+    send_challenge(env, bot, captcha, |a, b, minutes| {
+        let (a, b) = (a.to_string(), b.to_string());
+        let args = [user.first_name.as_str(), &a, &b, minutes];
+        i18n::format(lang, "captcha_new_member", &args)
+    })
+    .await
+}
+
+/// Restrict members who joined a group with captchas on and ask them to solve one.
+pub async fn handle_new_members(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<(), WorkerError> {
+    if !is_enabled(env, bot, m.chat.id.0).await? {
+        return Ok(());
+    }
+    let lang = i18n::language(m, env, bot).await?;
+    // a member who can't be restricted doesn't keep the others from getting their captcha
+    for user in m.new_chat_members.iter().filter(|user| !user.is_bot) {
+        if let Err(err) = challenge_member(m, env, bot, lang, user).await {
+            console_error!(
+                "Failed to ask {} to solve a captcha in {}: {}",
+                user.id.0,
+                m.chat.id.0,
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Ask users who want to join a group with captchas on to solve one in their private chat.
+pub async fn handle_join_request(
+    request: ChatJoinRequest,
+    env: &Env,
+    bot: &Bot<'_>,
+) -> Result<(), WorkerError> {
+    if !is_enabled(env, bot, request.chat.id.0).await? {
+        return Ok(());
+    }
+    let lang = user_lang(&request.from);
+    let title = match &request.chat.kind {
+        ChatType::Group { title, .. } | ChatType::Supergroup { title, .. } => title.clone(),
+        _ => String::new(),
+    };
+    let captcha = Captcha {
+        chat_id: request.chat.id.0,
+        user_id: request.from.id.0,
+        message_chat_id: request.user_chat_id,
+        message_id: 0,
+        answer: 0,
+        due: 0,
+        join_request: true,
+    };
+    send_challenge(env, bot, captcha, |a, b, minutes| {
+        let (a, b) = (a.to_string(), b.to_string());
+        i18n::format(lang, "captcha_join_request", &[&title, &a, &b, minutes])
+    })
+    .await
+}
+
+fn user_lang(user: &User) -> Lang {
+    user.language_code
+        .as_deref()
+        .and_then(Lang::from_code)
+        .unwrap_or_default()
+}
+
+// lets the member in with the default permissions of the group, or removes them when they failed
+async fn finish(
+    captcha: &Captcha,
+    solved: bool,
+    reply: Reply,
+    bot: &Bot<'_>,
+) -> Result<Reply, WorkerError> {
+    let (chat_id, user_id) = (captcha.chat_id, captcha.user_id);
+    let reply = match (captcha.join_request, solved) {
+        (true, true) => reply.then(ApproveChatJoinRequest { chat_id, user_id }),
+        (true, false) => reply.then(DeclineChatJoinRequest { chat_id, user_id }),
+        (false, true) => reply.then(RestrictChatMember {
+            chat_id,
+            user_id,
+            permissions: bot.default_permissions(chat_id).await?,
+            until_date: None,
+        }),
+        // unbanned right away, so they can try to join again
+        (false, false) => reply
+            .then(BanChatMember { chat_id, user_id })
+            .then(UnbanChatMember {
+                chat_id,
+                user_id,
+                only_if_banned: true,
+            }),
+    };
+    Ok(reply.delete(
+        ChatId(captcha.message_chat_id),
+        MessageId(captcha.message_id),
+    ))
+}
+
+/// Check the answer pressed by a member, `data` is the callback data after the prefix.
+pub async fn handle_callback(
+    query: &CallbackQuery,
+    data: &str,
+    env: &Env,
+    bot: &Bot<'_>,
+) -> Result<Reply, WorkerError> {
+    let lang = user_lang(&query.from);
+    let answer = |key: &str| {
+        Reply::call(AnswerCallbackQuery::new(
+            &query.id,
+            Some(i18n::text(lang, key).to_string()),
+        ))
+    };
+    let fields = data
+        .split(':')
+        .map(|field| field.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>();
+    let Some(&[chat_id, user_id, choice]) = fields.as_deref() else {
+        return Ok(answer("invalid_input"));
+    };
+    if user_id != query.from.id.0 {
+        return Ok(answer("captcha_not_yours"));
+    }
+    let store = bot.store(env)?;
+    let key = key(chat_id, user_id);
+    let Some(captcha) = store.get(&key).json::<Captcha>().await? else {
+        return Ok(answer("captcha_expired"));
+    };
+    let solved = choice == captcha.answer as i64;
+    console_log!(
+        "User {} answered the captcha of {}: {}",
+        user_id,
+        chat_id,
+        solved
+    );
+    let text = match solved {
+        true => "captcha_solved",
+        false => "captcha_failed",
+    };
+    // kept until the reply is ready, so the member can answer again when it fails
+    let reply = finish(&captcha, solved, answer(text), bot).await?;
+    // `KEY_NEXT_DUE` may now be early, which only costs an extra listing
+    store.delete(&key).await?;
+    Ok(reply)
+}
+
+/// Remove the members whose captcha expired.
+///
+/// The captchas are only listed when `KEY_NEXT_DUE` says one is due, and once every
+/// `RESCAN_MINUTES`, as KV allows few list operations a day.
+pub async fn expire_due(env: &Env, bot: &Bot<'_>) -> Result<(), WorkerError> {
+    let now = reminder::now();
+    let store = bot.store(env)?;
+    let next_due = store.get(KEY_NEXT_DUE).json::<i64>().await?;
+    let rescan = (now / 60) % RESCAN_MINUTES == 0;
+    if next_due.is_some_and(|next_due| next_due > now) && !rescan {
+        return Ok(());
+    }
+    let mut next_due = None::<i64>;
+    for key in store.list(KEY_PREFIX).await? {
+        let Some(captcha) = store.get(&key).json::<Captcha>().await? else {
+            continue;
+        };
+        if captcha.due > now {
+            next_due = Some(next_due.map_or(captcha.due, |due| due.min(captcha.due)));
+            continue;
+        }
+        console_log!("Captcha expired: {:?}", captcha);
+        for action in finish(&captcha, false, Reply::none(), bot)
+            .await?
+            .into_actions()
+        {
+            if let Err(err) = bot.execute(action).await {
+                console_error!("Failed to expire captcha {}: {}", key, err);
+            }
+        }
+        store.delete(&key).await?;
+    }
+    match next_due {
+        Some(next_due) => store.put(KEY_NEXT_DUE, next_due)?.execute().await?,
+        None => store.delete(KEY_NEXT_DUE).await?,
+    }
+    Ok(())
+}
+
+#[test]
+fn test_challenge() {
+    let seeds: [[u8; 32]; 3] = [
+        [0; 32],
+        [255; 32],
+        core::array::from_fn(|i| (i as u8).wrapping_mul(37)),
+    ];
+    for seed in seeds {
+        let (a, b, mut choices) = challenge(&seed);
+        assert!((1..=9).contains(&a) && (1..=9).contains(&b));
+        assert!(choices.contains(&(a + b)));
+        choices.sort();
+        choices.dedup();
+        assert_eq!(choices.len(), CHOICES);
+    }
+}
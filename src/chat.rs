@@ -1,14 +1,54 @@
+use serde::{Deserialize, Serialize};
 use telegram_types::bot::types::Message;
-use worker::{console_log, Env, Error as WorkerError};
+use worker::Error as WorkerError;
 
-use crate::{bot::Bot, bot_store, openai};
+use crate::{bot::Bot, console_log, openai, platform::Env};
+
+/// The last document shared in a chat, or its summary when it is too long.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatDocument {
+    pub file_name: String,
+    pub content: String,
+}
+
+pub async fn put_chat_document(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+    document: ChatDocument,
+) -> Result<(), WorkerError> {
+    let put = _bot
+        .store(_env)?
+        .put(&format!("INDEX_CHAT_DOCUMENT:{}", m.chat.id.0), document)?;
+    put.execute().await?;
+    Ok(())
+}
+
+pub async fn get_chat_document(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<Option<ChatDocument>, WorkerError> {
+    let get = _bot
+        .store(_env)?
+        .get(&format!("INDEX_CHAT_DOCUMENT:{}", m.chat.id.0))
+        .json::<ChatDocument>();
+    get.await
+}
 
 pub async fn put_chat_history(
     m: &Message,
     _env: &Env,
+    _bot: &Bot<'_>,
     msgs: Vec<openai::Message>,
 ) -> Result<(), WorkerError> {
-    let put = bot_store(&_env)?.put(&format!("INDEX_CHAT_HISTORY:{}", m.chat.id.0), msgs)?;
+    let msgs = msgs
+        .into_iter()
+        .map(openai::Message::without_images)
+        .collect::<Vec<openai::Message>>();
+    let put = _bot
+        .store(_env)?
+        .put(&format!("INDEX_CHAT_HISTORY:{}", m.chat.id.0), msgs)?;
     console_log!("{:?}", put);
     put.execute().await?;
     Ok(())
@@ -17,22 +57,88 @@ pub async fn put_chat_history(
 pub async fn get_chat_history(
     m: &Message,
     _env: &Env,
+    _bot: &Bot<'_>,
 ) -> Result<Vec<openai::Message>, WorkerError> {
-    let get = bot_store(&_env)?
+    let get = _bot
+        .store(_env)?
         .get(&format!("INDEX_CHAT_HISTORY:{}", m.chat.id.0))
         .json::<Vec<openai::Message>>();
     Ok(get.await?.unwrap_or(vec![]))
 }
 
-pub async fn clear_chat_history(m: &Message, _env: &Env) -> Result<(), WorkerError> {
-    bot_store(&_env)?
+pub async fn clear_chat_history(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<(), WorkerError> {
+    let store = _bot.store(_env)?;
+    store
         .delete(&format!("INDEX_CHAT_HISTORY:{}", m.chat.id.0))
         .await?;
+    store
+        .delete(&format!("INDEX_CHAT_DOCUMENT:{}", m.chat.id.0))
+        .await?;
     Ok(())
 }
 
 const PREFER_CONTEXT_LENGTH: usize = 5;
 
+/// Chat model of the chat, set from the settings app.
+pub async fn get_chat_model(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<Option<String>, WorkerError> {
+    let get = _bot
+        .store(_env)?
+        .get(&format!("INDEX_CHAT_MODEL:{}", m.chat.id.0));
+    get.text().await
+}
+
+/// Earlier messages sent to the model with a new one, set from the settings app.
+pub async fn get_history_length(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<usize, WorkerError> {
+    let get = _bot
+        .store(_env)?
+        .get(&format!("INDEX_CHAT_HISTORY_LENGTH:{}", m.chat.id.0));
+    Ok(get
+        .text()
+        .await?
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(PREFER_CONTEXT_LENGTH))
+}
+
+/// Tokens the chat model used in the chat so far.
+pub async fn get_chat_usage(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<openai::Usage, WorkerError> {
+    let get = _bot
+        .store(_env)?
+        .get(&format!("INDEX_CHAT_USAGE:{}", m.chat.id.0));
+    Ok(get.json().await?.unwrap_or_default())
+}
+
+// KV has no increments, concurrent answers may lose a count, and each one costs a write
+pub async fn add_chat_usage(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+    usage: &openai::Usage,
+) -> Result<(), WorkerError> {
+    let mut total = get_chat_usage(m, _env, _bot).await?;
+    total.add(usage);
+    _bot.store(_env)?
+        .put(&format!("INDEX_CHAT_USAGE:{}", m.chat.id.0), total)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
 pub async fn build_message_context(
     m: &Message,
     mut history: Vec<openai::Message>,
@@ -40,20 +146,27 @@ pub async fn build_message_context(
     _bot: Bot<'_>,
 ) -> Result<Vec<openai::Message>, WorkerError> {
     let mut msgs = vec![];
-    if let Some(chat_env) = _env
-        .kv("FDKEVIN_BOT_STORE")?
+    if let Some(chat_env) = _bot
+        .store(_env)?
         .get(&format!("INDEX_CHAT_ENV:{}", m.chat.id.0))
         .text()
         .await?
     {
         msgs.push(openai::Message::new("system", &chat_env))
     }
-    history = history
-        .into_iter()
-        .filter(|msg| msg.role != "system")
-        .collect();
-    if history.len() > PREFER_CONTEXT_LENGTH {
-        history.drain(..history.len() - PREFER_CONTEXT_LENGTH);
+    if let Some(document) = get_chat_document(m, _env, &_bot).await? {
+        msgs.push(openai::Message::new(
+            "system",
+            &format!(
+                "The user shared the document `{}`, its content follows.\n\n{}",
+                document.file_name, document.content
+            ),
+        ))
+    }
+    history.retain(|msg| msg.role != "system");
+    let length = get_history_length(m, _env, &_bot).await?;
+    if history.len() > length {
+        history.drain(..history.len() - length);
     }
 
     msgs.append(&mut history);
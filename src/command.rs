@@ -1,94 +1,192 @@
-use std::cmp::Ordering;
-
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use chrono::{TimeZone, Utc};
+use chrono_tz::Tz;
 use telegram_types::bot::{
-    methods::{ChatTarget, SendMessage},
-    types::Message,
+    methods::{ChatTarget, SendMessage, SendPhoto},
+    types::{ChatType, FileToSend, Message},
 };
-use worker::{console_log, Env, Error as WorkerError, Response, Url};
+use worker::{Error as WorkerError, Url};
 
 use crate::{
-    bot::{Bot, WebhookReply},
-    bot_store,
-    chat::{build_message_context, clear_chat_history, get_chat_history, put_chat_history},
+    args::{Args, Kind, Parser},
+    bot::Bot,
+    captcha,
+    chat::{
+        add_chat_usage, build_message_context, clear_chat_history, get_chat_history,
+        get_chat_model, get_chat_usage, get_history_length, put_chat_document, put_chat_history,
+        ChatDocument,
+    },
+    console_log, credits,
+    dialog::{self, Dialog},
+    document,
+    feedback::{self, ChatReply},
+    i18n::{self, Lang},
+    invocation::message_args,
+    media,
+    methods::{InputMediaPhoto, SendMediaGroup},
+    middleware,
+    moderation::{self, Moderation},
     openai,
+    platform::{self, Env},
+    profile,
+    reminder::{self, Reminder},
+    reply::Reply,
+    title::{self, TitleConfig},
+    webapp::{self, SettingsChat},
 };
 
+const MAX_CAPTION_LENGTH: usize = 1024;
+
 pub fn return_reply_message<S: AsRef<str>>(
     message: &Message,
     reply: S,
-) -> Result<Response, WorkerError> {
-    Response::from_json(&WebhookReply::from(
+) -> Result<Reply, WorkerError> {
+    Ok(Reply::none().send(
         SendMessage::new(ChatTarget::Id(message.chat.id), reply.as_ref()).reply(message.message_id),
     ))
 }
 
-pub fn return_message<S: AsRef<str>>(message: &Message, reply: S) -> Result<Response, WorkerError> {
-    Response::from_json(&WebhookReply::from(SendMessage::new(
+pub fn return_message<S: AsRef<str>>(message: &Message, reply: S) -> Result<Reply, WorkerError> {
+    Ok(Reply::none().send(SendMessage::new(
         ChatTarget::Id(message.chat.id),
         reply.as_ref(),
     )))
 }
 
-pub async fn start(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
+pub async fn start(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
     let reply = format!("FDKevin bot {}", env!("CARGO_PKG_VERSION"));
     console_log!("Replied: {:?}", reply);
     return_reply_message(&m, reply)
 }
 
-pub async fn chat_info(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
+pub async fn chat_info(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
     let chat_id = format!("{:#?}", m.chat);
     return_reply_message(&m, chat_id)
 }
 
-pub async fn echo(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let text = if let Some(msg) = m.text.clone().unwrap().split_once(' ') {
-        msg.1.to_string()
-    } else {
-        "wut?".to_string()
+pub fn echo_args() -> Parser {
+    Parser::new("echo").rest("text")
+}
+
+pub async fn echo(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match echo_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
     };
-    return_reply_message(&m, text)
+    return_reply_message(&m, args.text("text").unwrap_or_default())
+}
+
+pub fn help_args() -> Parser {
+    Parser::new("help").optional("command", Kind::Text)
 }
 
-pub async fn help(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let mut reply = "Available commands:".to_string();
-    for (key, _) in _bot.commands {
-        reply = reply + "\n\t/" + &key.clone();
+pub async fn help(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match help_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    if let Some(name) = args.text("command") {
+        let name = name.trim_start_matches('/').to_ascii_lowercase();
+        let Some((name, command)) = _bot.resolve(&name) else {
+            let reply = i18n::format(_bot.lang, "unknown_command", &[&name])
+                + i18n::text(_bot.lang, "send_help");
+            return return_reply_message(&m, reply);
+        };
+        let info = &command.info;
+        let lang = _bot.lang;
+        let mut reply = format!("/{} - {}", name, info.description_in(Some(lang.code())));
+        if !info.aliases.is_empty() {
+            reply =
+                reply + "\n" + &i18n::format(lang, "help_aliases", &[&info.aliases.join(", /")]);
+        }
+        if let Some(usage) = &info.usage {
+            reply = reply + "\n" + &i18n::format(lang, "help_usage", &[usage]);
+        }
+        if !info.examples.is_empty() {
+            reply = reply + "\n" + i18n::text(lang, "help_examples");
+            for example in &info.examples {
+                reply = reply + "\n\t" + example;
+            }
+        }
+        return return_reply_message(&m, reply);
+    }
+    let lang = _bot.lang;
+    let mut reply = i18n::text(lang, "help_title").to_string();
+    let mut category = "";
+    for (name, info) in _bot.sorted_commands() {
+        if info.hidden {
+            continue;
+        }
+        if info.category != category {
+            category = &info.category;
+            reply = reply + "\n\n" + i18n::text(lang, category);
+        }
+        reply = reply + "\n\t/" + name + " - " + info.description_in(Some(lang.code()));
     }
+    reply = reply + "\n\n" + i18n::text(lang, "help_details");
     return_reply_message(&m, reply)
 }
 
-pub async fn fetch(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let text = if let Some(msg) = m.text.clone().unwrap().split_once(' ') {
-        let url = Url::parse(msg.1).unwrap();
-        let mut resp = worker::Fetch::Url(url).send().await?;
-        resp.text().await.unwrap()
-    } else {
-        "You need to input a url".to_string()
+pub fn fetch_args() -> Parser {
+    Parser::new("fetch").arg("url", Kind::Url)
+}
+
+pub async fn fetch(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match fetch_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
     };
-    return_reply_message(&m, text)
+    let url = args.url("url").cloned().expect("required argument");
+    let resp = platform::fetch(worker::Method::Get, url.as_str(), &[], None).await?;
+    return_reply_message(&m, resp.text()?)
 }
 
-pub async fn set_chat_env(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let text = if let Some(msg) = m.text.clone().unwrap().split_once(' ') {
-        if msg.1 == "" {
-            "Shoud not be empty"
-        } else {
-            let put = bot_store(&_env)?.put(&format!("INDEX_CHAT_ENV:{}", m.chat.id.0), msg.1)?;
-            console_log!("{:?}", put);
-            put.execute().await?;
-            "Success"
-        }
-    } else {
-        "Error parse prompt"
+pub fn set_chat_env_args() -> Parser {
+    Parser::new("set_chat_env").rest("prompt")
+}
+
+pub async fn set_chat_env(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    if message_args(&m).is_none() {
+        dialog::save(&m, &_env, &_bot, &Dialog::new("set_chat_env", "prompt")).await?;
+        return return_reply_message(&m, i18n::text(_bot.lang, "ask_system_prompt"));
+    }
+    let args = match set_chat_env_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
     };
-    return_reply_message(&m, text)
+    let prompt = args.text("prompt").unwrap_or_default();
+    let put = _bot
+        .store(&_env)?
+        .put(&format!("INDEX_CHAT_ENV:{}", m.chat.id.0), prompt)?;
+    console_log!("{:?}", put);
+    put.execute().await?;
+    return_reply_message(&m, i18n::text(_bot.lang, "success"))
 }
 
-pub async fn get_chat_env(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let get = bot_store(&_env)?.get(&format!("INDEX_CHAT_ENV:{}", m.chat.id.0));
-    let text = get.text().await?.unwrap_or("env not set".to_string());
+pub async fn set_chat_env_dialog(
+    m: Message,
+    _env: Env,
+    _bot: Bot<'_>,
+    _dialog: Dialog,
+) -> Result<Reply, WorkerError> {
+    let Some(prompt) = m.text.clone() else {
+        return return_reply_message(&m, i18n::text(_bot.lang, "ask_system_prompt_text"));
+    };
+    dialog::finish(&m, &_env, &_bot).await?;
+    let put = _bot
+        .store(&_env)?
+        .put(&format!("INDEX_CHAT_ENV:{}", m.chat.id.0), prompt)?;
+    put.execute().await?;
+    return_reply_message(&m, i18n::text(_bot.lang, "success"))
+}
+
+pub async fn get_chat_env(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let get = _bot
+        .store(&_env)?
+        .get(&format!("INDEX_CHAT_ENV:{}", m.chat.id.0));
+    let text = get
+        .text()
+        .await?
+        .unwrap_or(i18n::text(_bot.lang, "env_not_set").to_string());
     return_reply_message(&m, text)
 }
 
@@ -96,18 +194,28 @@ pub async fn clear_chat_context(
     m: Message,
     _env: Env,
     _bot: Bot<'_>,
-) -> Result<Response, WorkerError> {
-    let msg = match clear_chat_history(&m, &_env).await {
-        Ok(_) => "success".to_string(),
+) -> Result<Reply, WorkerError> {
+    let msg = match clear_chat_history(&m, &_env, &_bot).await {
+        Ok(_) => i18n::text(_bot.lang, "success").to_string(),
         Err(err) => err.to_string(),
     };
     return_reply_message(&m, msg)
 }
 
 // user openai key getter
-pub async fn get_user_openai_key(m: &Message, _env: &Env) -> Result<Option<String>, WorkerError> {
-    let get = bot_store(&_env)?.get(&format!("USER_OPENAI_KEY:{}", m.chat.id.0));
-    Ok(get.text().await?)
+pub async fn get_user_openai_key(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<Option<String>, WorkerError> {
+    let get = _bot
+        .store(_env)?
+        .get(&format!("USER_OPENAI_KEY:{}", m.chat.id.0));
+    get.text().await
+}
+
+pub fn set_user_openai_key_args() -> Parser {
+    Parser::new("set_openai_key").arg("key", Kind::Text)
 }
 
 // user openai key setter
@@ -115,119 +223,1019 @@ pub async fn set_user_openai_key(
     m: Message,
     _env: Env,
     _bot: Bot<'_>,
-) -> Result<Response, WorkerError> {
-    let text = if let Some(msg) = m.text.clone().unwrap().split_once(' ') {
-        if msg.1 == "" {
-            "Shoud not be empty"
-        } else {
-            let put = bot_store(&_env)?.put(&format!("USER_OPENAI_KEY:{}", m.chat.id.0), msg.1)?;
-            console_log!("{:?}", put);
-            put.execute().await?;
-            "Success"
-        }
-    } else {
-        "Error parse prompt"
+) -> Result<Reply, WorkerError> {
+    if message_args(&m).is_none() {
+        dialog::save(&m, &_env, &_bot, &Dialog::new("set_openai_key", "key")).await?;
+        return return_reply_message(&m, i18n::text(_bot.lang, "ask_openai_key"));
+    }
+    let args = match set_user_openai_key_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
     };
-    return_reply_message(&m, text)
+    let key = args.text("key").unwrap_or_default();
+    _bot.store(&_env)?
+        .put(&format!("USER_OPENAI_KEY:{}", m.chat.id.0), key)?
+        .execute()
+        .await?;
+    // the key shouldn't stay in the chat
+    Ok(return_message(&m, i18n::text(_bot.lang, "success"))?.delete(m.chat.id, m.message_id))
+}
+
+// asks for the key, then for the endpoint to use it with
+pub async fn set_user_openai_key_dialog(
+    m: Message,
+    _env: Env,
+    _bot: Bot<'_>,
+    _dialog: Dialog,
+) -> Result<Reply, WorkerError> {
+    let text = m.text.clone().unwrap_or_default();
+    let text = text.trim();
+    if text.is_empty() {
+        return return_reply_message(&m, i18n::text(_bot.lang, "ask_text"));
+    }
+    if _dialog.step == "key" {
+        let next = _dialog.with("key", text).step("endpoint");
+        dialog::save(&m, &_env, &_bot, &next).await?;
+        // the key shouldn't stay in the chat
+        let reply = return_message(&m, i18n::text(_bot.lang, "ask_openai_endpoint"))?;
+        return Ok(reply.delete(m.chat.id, m.message_id));
+    }
+    let store = _bot.store(&_env)?;
+    let endpoint_key = format!("USER_OPENAI_ENDPOINT:{}", m.chat.id.0);
+    if text == "default" {
+        store.delete(&endpoint_key).await?;
+    } else if Url::parse(text).is_ok() {
+        store.put(&endpoint_key, text)?.execute().await?;
+    } else {
+        return return_reply_message(&m, i18n::text(_bot.lang, "not_url_or_default"));
+    }
+    let key = _dialog.get("key").unwrap_or_default();
+    store
+        .put(&format!("USER_OPENAI_KEY:{}", m.chat.id.0), key)?
+        .execute()
+        .await?;
+    dialog::finish(&m, &_env, &_bot).await?;
+    return_reply_message(&m, i18n::text(_bot.lang, "success"))
 }
 
 pub async fn get_user_openai_endpoint(
     m: &Message,
     _env: &Env,
+    _bot: &Bot<'_>,
 ) -> Result<Option<String>, WorkerError> {
-    let get = bot_store(&_env)?.get(&format!("USER_OPENAI_ENDPOINT:{}", m.chat.id.0));
-    Ok(get.text().await?)
+    let get = _bot
+        .store(_env)?
+        .get(&format!("USER_OPENAI_ENDPOINT:{}", m.chat.id.0));
+    get.text().await
+}
+
+pub fn set_user_openai_endpoint_args() -> Parser {
+    Parser::new("set_openai_endpoint").arg("endpoint", Kind::Url)
 }
 
 pub async fn set_user_openai_endpoint(
     m: Message,
     _env: Env,
     _bot: Bot<'_>,
-) -> Result<Response, WorkerError> {
-    let text = if let Some(msg) = m.text.clone().unwrap().split_once(' ') {
-        if msg.1 == "" {
-            "Shoud not be empty"
-        } else {
-            let put =
-                bot_store(&_env)?.put(&format!("USER_OPENAI_ENDPOINT:{}", m.chat.id.0), msg.1)?;
-            console_log!("{:?}", put);
-            put.execute().await?;
-            "Success"
+) -> Result<Reply, WorkerError> {
+    let args = match set_user_openai_endpoint_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let endpoint = args.url("endpoint").map(Url::as_str).unwrap_or_default();
+    let put = _bot
+        .store(&_env)?
+        .put(&format!("USER_OPENAI_ENDPOINT:{}", m.chat.id.0), endpoint)?;
+    console_log!("{:?}", put);
+    put.execute().await?;
+    return_reply_message(&m, i18n::text(_bot.lang, "success"))
+}
+
+// per-chat openai key and endpoint, falling back to the global key and the default endpoint.
+// the endpoint of a chat is only used with its own key, the global one isn't sent elsewhere
+pub async fn openai_config(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+) -> Result<(String, Option<String>), WorkerError> {
+    match get_user_openai_key(m, _env, _bot).await? {
+        Some(key) => Ok((key, get_user_openai_endpoint(m, _env, _bot).await?)),
+        None => Ok((_bot.secret(_env, "OPENAI_KEY")?, None)),
+    }
+}
+
+pub async fn get_voice_chat(m: &Message, _env: &Env, _bot: &Bot<'_>) -> Result<bool, WorkerError> {
+    let get = _bot
+        .store(_env)?
+        .get(&format!("INDEX_CHAT_VOICE_CHAT:{}", m.chat.id.0));
+    Ok(get.text().await?.as_deref() == Some("on"))
+}
+
+pub fn set_voice_chat_args() -> Parser {
+    Parser::new("set_voice_chat").arg("value", Kind::Choice(&["on", "off"]))
+}
+
+// whether transcribed voice messages are answered by the chat model
+pub async fn set_voice_chat(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match set_voice_chat_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let value = args.text("value").unwrap_or_default();
+    let put = _bot
+        .store(&_env)?
+        .put(&format!("INDEX_CHAT_VOICE_CHAT:{}", m.chat.id.0), value)?;
+    console_log!("{:?}", put);
+    put.execute().await?;
+    return_reply_message(&m, i18n::text(_bot.lang, "success"))
+}
+
+// settings of a group are changed by its administrators, the catalog key of the refusal otherwise
+async fn group_admin_refusal(
+    m: &Message,
+    _bot: &Bot<'_>,
+) -> Result<Option<&'static str>, WorkerError> {
+    if !matches!(
+        m.chat.kind,
+        ChatType::Group { .. } | ChatType::Supergroup { .. }
+    ) {
+        return Ok(Some("groups_only"));
+    }
+    let Some(user) = &m.from else {
+        return Ok(Some("settings_admins_only"));
+    };
+    match _bot.is_admin(ChatTarget::Id(m.chat.id), user.id).await? {
+        true => Ok(None),
+        false => Ok(Some("settings_admins_only")),
+    }
+}
+
+pub fn set_captcha_args() -> Parser {
+    Parser::new("set_captcha").arg("value", Kind::Choice(&["on", "off"]))
+}
+
+// new members of the group have to solve a captcha before they can write
+pub async fn set_captcha(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let lang = _bot.lang;
+    if let Some(refusal) = group_admin_refusal(&m, &_bot).await? {
+        return return_reply_message(&m, i18n::text(lang, refusal));
+    }
+    let args = match set_captcha_args().parse_message(&m, lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    // members of basic groups can't be restricted
+    if matches!(m.chat.kind, ChatType::Group { .. }) {
+        return return_reply_message(&m, i18n::text(lang, "captcha_supergroups_only"));
+    }
+    let value = args.text("value").unwrap_or_default();
+    captcha::set_enabled(&_env, &_bot, m.chat.id.0, value).await?;
+    return_reply_message(&m, i18n::text(lang, "success"))
+}
+
+pub fn set_moderation_args() -> Parser {
+    Parser::new("set_moderation")
+        .arg("action", Kind::Choice(moderation::ACTIONS))
+        .optional_rest("policy")
+}
+
+// messages of new members are checked against the policy by the chat model
+pub async fn set_moderation(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let lang = _bot.lang;
+    if let Some(refusal) = group_admin_refusal(&m, &_bot).await? {
+        return return_reply_message(&m, i18n::text(lang, refusal));
+    }
+    let args = match set_moderation_args().parse_message(&m, lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let moderation = match args.text("action").unwrap_or_default() {
+        "off" => None,
+        action => Some(Moderation {
+            action: action.to_string(),
+            policy: args.text("policy").map(str::to_string),
+        }),
+    };
+    moderation::set_moderation(&_env, &_bot, m.chat.id.0, moderation).await?;
+    return_reply_message(&m, i18n::text(lang, "success"))
+}
+
+// shows only enough of a key to tell keys apart
+fn mask_key(key: &str) -> String {
+    let chars = key.chars().collect::<Vec<char>>();
+    match chars.len() {
+        0..=10 => "…".to_string(),
+        len => format!(
+            "{}…{}",
+            chars[..3].iter().collect::<String>(),
+            chars[len - 4..].iter().collect::<String>()
+        ),
+    }
+}
+
+fn on_off(lang: Lang, on: bool) -> &'static str {
+    match on {
+        true => i18n::text(lang, "state_on"),
+        false => i18n::text(lang, "state_off"),
+    }
+}
+
+// everything that decides how the bot answers in this chat, one line each
+pub async fn status(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let lang = _bot.lang;
+    let chat_id = m.chat.id.0;
+    let is_group = matches!(
+        m.chat.kind,
+        ChatType::Group { .. } | ChatType::Supergroup { .. }
+    );
+    let mut lines = vec![];
+
+    let access = match middleware::is_allowed(&_bot, &_env, chat_id) {
+        true => i18n::text(lang, "access_allowed").to_string(),
+        false => {
+            let balance = credits::balance(&_env, &_bot, credits::user_id(&m)).await?;
+            i18n::format(lang, "access_credits", &[&balance.to_string()])
         }
-    } else {
-        "Error parse prompt"
     };
+    lines.push(i18n::format(lang, "status_access", &[&access]));
+    let language = i18n::language(&m, &_env, &_bot).await?;
+    lines.push(i18n::format(lang, "status_language", &[language.code()]));
+    // groups only get answers to messages addressed to the bot, see `default_reply`
+    let trigger = match is_group {
+        true => "trigger_group",
+        false => "trigger_private",
+    };
+    lines.push(i18n::format(
+        lang,
+        "status_trigger",
+        &[i18n::text(lang, trigger)],
+    ));
+    let voice_chat = get_voice_chat(&m, &_env, &_bot).await?;
+    lines.push(i18n::format(
+        lang,
+        "status_voice_chat",
+        &[on_off(lang, voice_chat)],
+    ));
+
+    let model = get_chat_model(&m, &_env, &_bot)
+        .await?
+        .unwrap_or(openai::DEFAULT_CHAT_MODEL.to_string());
+    lines.push(i18n::format(lang, "status_model", &[&model]));
+    // the endpoint of the chat is only used with its own key, see `openai_config`
+    let user_key = get_user_openai_key(&m, &_env, &_bot).await?;
+    let user_endpoint = match user_key {
+        Some(_) => get_user_openai_endpoint(&m, &_env, &_bot).await?,
+        None => None,
+    };
+    let endpoint = openai::chat_endpoint(user_endpoint);
+    let provider = Url::parse(&endpoint)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    lines.push(i18n::format(lang, "status_provider", &[&provider]));
+    lines.push(i18n::format(lang, "status_endpoint", &[&endpoint]));
+    let (key, source) = match user_key {
+        Some(key) => (Some(key), "key_source_chat"),
+        None => (_bot.secret(&_env, "OPENAI_KEY").ok(), "key_source_global"),
+    };
+    let key = match key {
+        Some(key) => i18n::format(
+            lang,
+            "status_key",
+            &[&mask_key(&key), i18n::text(lang, source)],
+        ),
+        None => i18n::text(lang, "status_no_key").to_string(),
+    };
+    lines.push(key);
+    let history_length = get_history_length(&m, &_env, &_bot).await?;
+    lines.push(i18n::format(
+        lang,
+        "status_history",
+        &[&history_length.to_string()],
+    ));
+    let usage = get_chat_usage(&m, &_env, &_bot).await?;
+    lines.push(i18n::format(
+        lang,
+        "status_usage",
+        &[
+            &usage.total_tokens.to_string(),
+            &usage.prompt_tokens.to_string(),
+            &usage.completion_tokens.to_string(),
+        ],
+    ));
+
+    let tz = reminder::chat_timezone(&m, &_env, &_bot).await?;
+    lines.push(i18n::format(lang, "status_timezone", &[tz.name()]));
+    let rate_limit = match middleware::rate_limit_state(&_bot, &_env, chat_id).await? {
+        Some((count, limit)) => i18n::format(
+            lang,
+            "status_rate_limit",
+            &[&count.to_string(), &limit.to_string()],
+        ),
+        None => i18n::text(lang, "status_no_rate_limit").to_string(),
+    };
+    lines.push(rate_limit);
+
+    if is_group {
+        let captcha = captcha::is_enabled(&_env, &_bot, chat_id).await?;
+        lines.push(i18n::format(
+            lang,
+            "status_captcha",
+            &[on_off(lang, captcha)],
+        ));
+        let moderation = match moderation::get_moderation(&_env, &_bot, chat_id).await? {
+            Some(moderation) => moderation.action,
+            None => i18n::text(lang, "state_off").to_string(),
+        };
+        lines.push(i18n::format(lang, "status_moderation", &[&moderation]));
+        let config = title::load(&_env, &_bot, chat_id).await?;
+        lines.push(i18n::format(
+            lang,
+            "status_title",
+            &[on_off(lang, config.enabled)],
+        ));
+        if !config.segments.is_empty() {
+            lines.push(i18n::format(
+                lang,
+                "status_template",
+                &[&config.segments.join(&config.delimiter)],
+            ));
+            lines.push(i18n::format(lang, "status_delimiter", &[&config.delimiter]));
+            lines.push(i18n::format(
+                lang,
+                "status_preview",
+                &[&config.render(title::now_in(tz))],
+            ));
+        }
+    }
+    return_reply_message(&m, lines.join("\n"))
+}
+
+// changes the title template of the group and shows the title it renders to
+async fn edit_title<F: FnOnce(&mut TitleConfig) -> Result<(), &'static str>>(
+    m: &Message,
+    _env: &Env,
+    _bot: &Bot<'_>,
+    edit: F,
+) -> Result<Reply, WorkerError> {
+    let lang = _bot.lang;
+    if let Some(refusal) = group_admin_refusal(m, _bot).await? {
+        return return_reply_message(m, i18n::text(lang, refusal));
+    }
+    let mut config = title::load(_env, _bot, m.chat.id.0).await?;
+    if let Err(key) = edit(&mut config) {
+        return return_reply_message(m, i18n::text(lang, key));
+    }
+    title::save(_env, _bot, m.chat.id.0, &config).await?;
+    let tz = reminder::chat_timezone(m, _env, _bot).await?;
+    let title = config.render(title::now_in(tz));
+    let mut reply = i18n::format(lang, "title_preview", &[&title]);
+    if !config.enabled {
+        reply += i18n::text(lang, "title_enable_hint");
+    }
+    return_reply_message(m, reply)
+}
+
+pub async fn enable(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    edit_title(&m, &_env, &_bot, |config| {
+        config.enabled = true;
+        Ok(())
+    })
+    .await
+}
+
+pub async fn disable(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    edit_title(&m, &_env, &_bot, |config| {
+        config.enabled = false;
+        // set again when enabled, someone may have renamed the group meanwhile
+        config.last_title = None;
+        Ok(())
+    })
+    .await
+}
+
+pub fn set_template_args() -> Parser {
+    Parser::new("set_template").optional_rest("template")
+}
+
+pub async fn set_template(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match set_template_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let template = args.text("template").unwrap_or_default().to_string();
+    edit_title(&m, &_env, &_bot, |config| {
+        config.set_template(&template);
+        Ok(())
+    })
+    .await
+}
+
+pub fn set_delimiter_args() -> Parser {
+    Parser::new("set_delimiter").arg("delimiter", Kind::Text)
+}
+
+pub async fn set_delimiter(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match set_delimiter_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let delimiter = args.text("delimiter").unwrap_or_default().to_string();
+    edit_title(&m, &_env, &_bot, |config| {
+        config.delimiter = delimiter;
+        Ok(())
+    })
+    .await
+}
+
+pub fn push_args() -> Parser {
+    Parser::new("push").rest("segment")
+}
+
+pub async fn push(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match push_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let segment = args.text("segment").unwrap_or_default().to_string();
+    edit_title(&m, &_env, &_bot, |config| {
+        config.segments.push(segment);
+        Ok(())
+    })
+    .await
+}
+
+pub fn push_front_args() -> Parser {
+    Parser::new("push_front").rest("segment")
+}
+
+pub async fn push_front(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match push_front_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let segment = args.text("segment").unwrap_or_default().to_string();
+    edit_title(&m, &_env, &_bot, |config| {
+        config.segments.insert(0, segment);
+        Ok(())
+    })
+    .await
+}
+
+pub async fn pop(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    edit_title(&m, &_env, &_bot, |config| {
+        config.segments.pop().map(|_| ()).ok_or("template_empty")
+    })
+    .await
+}
+
+pub async fn pop_front(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    edit_title(&m, &_env, &_bot, |config| {
+        match config.segments.is_empty() {
+            true => Err("template_empty"),
+            false => {
+                config.segments.remove(0);
+                Ok(())
+            }
+        }
+    })
+    .await
+}
+
+pub async fn cancel(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let text = match dialog::load(&m, &_env, &_bot).await? {
+        Some(_) => {
+            dialog::finish(&m, &_env, &_bot).await?;
+            "cancelled"
+        }
+        None => "nothing_to_cancel",
+    };
+    let text = i18n::text(_bot.lang, text);
     return_reply_message(&m, text)
 }
 
-pub async fn call_chat_api(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    _bot.send_chat_action(m.chat.id.0, "typing").await?;
-    let raw_text = m.text.clone().unwrap();
-    let msg = match raw_text.starts_with("/") {
-        true => match raw_text.split_once(' ') {
-            Some(msg) => msg.1,
-            None => return return_reply_message(&m, "invalid input"),
-        },
-        false => raw_text.as_str(),
-    };
-    let history = get_chat_history(&m, &_env).await?;
-    let mut msgs = build_message_context(&m, history, &_env, _bot).await?;
-    msgs.push(openai::Message::new("user", msg));
-    let key = match get_user_openai_key(&m, &_env).await? {
-        Some(_key) => _key,
-        None => _env.secret("OPENAI_KEY")?.to_string(),
-    };
-    let endpoint = get_user_openai_endpoint(&m, &_env).await?;
-    let reply = match openai::call_chat_api(&msgs, key, endpoint).await {
-        Ok(reply) => {
-            msgs.push(openai::Message::new("assistant", &reply));
-            put_chat_history(&m, &_env, msgs).await?;
-            reply
+pub fn remind_args() -> Parser {
+    Parser::new("remind").rest("when")
+}
+
+pub async fn remind(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match remind_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(_) => return return_reply_message(&m, i18n::text(_bot.lang, "remind_usage")),
+    };
+    let tz = reminder::chat_timezone(&m, &_env, &_bot).await?;
+    let now = Utc
+        .timestamp_opt(reminder::now(), 0)
+        .single()
+        .unwrap_or_default()
+        .with_timezone(&tz);
+    let when = match reminder::parse_when(args.text("when").unwrap_or_default(), now, _bot.lang) {
+        Ok(when) => when,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let reminder = Reminder {
+        id: m.message_id.0,
+        chat_id: m.chat.id.0,
+        text: when.text,
+        due: when.due.timestamp(),
+        repeat: when.repeat,
+        time: Some(reminder::time_of_day(when.due, tz)),
+    };
+    reminder::add(&_env, &_bot, &reminder).await?;
+    let lang = _bot.lang;
+    let mut reply = i18n::format(lang, "reminder_set", &[&reminder.due_in(tz), tz.name()]);
+    if let Some(repeat) = reminder.repeat {
+        reply += &i18n::format(
+            lang,
+            "reminder_repeat",
+            &[&reminder::format_duration(repeat)],
+        );
+    }
+    reply += &i18n::format(lang, "reminder_cancel_hint", &[&reminder.id.to_string()]);
+    return_reply_message(&m, reply)
+}
+
+pub async fn reminders(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let reminders = reminder::list(&m, &_env, &_bot).await?;
+    if reminders.is_empty() {
+        return return_reply_message(&m, i18n::text(_bot.lang, "no_reminders"));
+    }
+    let tz = reminder::chat_timezone(&m, &_env, &_bot).await?;
+    let mut reply = i18n::format(_bot.lang, "reminders_title", &[tz.name()]);
+    for reminder in reminders {
+        reply = reply + "\n" + &reminder.id.to_string() + " - " + &reminder.due_in(tz);
+        if let Some(repeat) = reminder.repeat {
+            let repeat = reminder::format_duration(repeat);
+            reply += &i18n::format(_bot.lang, "reminder_every", &[&repeat]);
         }
-        Err(err) => format!("{}", err),
+        reply = reply + " - " + &reminder.text;
+    }
+    return_reply_message(&m, reply)
+}
+
+pub fn cancel_reminder_args() -> Parser {
+    Parser::new("cancel_reminder").arg("id", Kind::Int)
+}
+
+pub async fn cancel_reminder(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match cancel_reminder_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let id = args.int("id").unwrap_or_default();
+    let text = match reminder::remove(&m, &_env, &_bot, id).await? {
+        true => "reminder_cancelled",
+        false => "no_such_reminder",
     };
-    return_message(&m, reply)
+    let text = i18n::text(_bot.lang, text);
+    return_reply_message(&m, text)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Command {
-    command: String,
-    description: String,
+pub fn set_timezone_args() -> Parser {
+    Parser::new("set_timezone").arg("timezone", Kind::Text)
 }
 
-pub async fn sync_commands(_m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let mut commands = vec![];
-    for cmd in _bot.commands {
-        commands.push(Command {
-            command: format!("/{}", cmd.0.clone()),
-            description: cmd.0,
-        })
+pub async fn set_timezone(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match set_timezone_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let timezone = args.text("timezone").unwrap_or_default();
+    let Ok(tz) = timezone.parse::<Tz>() else {
+        return return_reply_message(&m, i18n::format(_bot.lang, "unknown_timezone", &[timezone]));
+    };
+    _bot.store(&_env)?
+        .put(&format!("INDEX_CHAT_TIMEZONE:{}", m.chat.id.0), tz.name())?
+        .execute()
+        .await?;
+    return_reply_message(&m, i18n::text(_bot.lang, "success"))
+}
+
+pub fn set_language_args() -> Parser {
+    Parser::new("set_language").arg("language", Kind::Choice(&["en", "zh", "auto"]))
+}
+
+// `auto` answers every user in the language of their Telegram app
+pub async fn set_language(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match set_language_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let lang = Lang::from_code(args.text("language").unwrap_or_default());
+    i18n::set_language(&m, &_env, &_bot, lang).await?;
+    let lang = i18n::language(&m, &_env, &_bot).await?;
+    return_reply_message(&m, i18n::text(lang, "success"))
+}
+
+// groups are added to the settings app of the admin who asks for it
+pub async fn settings(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let lang = _bot.lang;
+    let title = match &m.chat.kind {
+        ChatType::Group { title, .. } | ChatType::Supergroup { title, .. } => title.clone(),
+        _ => return return_reply_message(&m, i18n::text(lang, "settings_private")),
+    };
+    if let Some(refusal) = group_admin_refusal(&m, &_bot).await? {
+        return return_reply_message(&m, i18n::text(lang, refusal));
     }
-    commands.sort_by(|a, b| a.command.partial_cmp(&b.command).unwrap_or(Ordering::Equal));
-    let body = json!({
-        "method": "setMyCommands",
-        "commands": commands,
+    let Some(user) = &m.from else {
+        return Ok(Reply::none());
+    };
+    let chat = SettingsChat {
+        id: m.chat.id.0,
+        title,
+    };
+    webapp::register_chat(&_bot, &_env, user.id.0, chat).await?;
+    return_reply_message(&m, i18n::text(lang, "settings_group"))
+}
+
+pub fn buy_args() -> Parser {
+    Parser::new("buy").optional("package", Kind::Choice(credits::PACKAGE_NAMES))
+}
+
+pub async fn buy(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let args = match buy_args().parse_message(&m, _bot.lang) {
+        Ok(args) => args,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    let name = args.text("package");
+    match credits::PACKAGES
+        .iter()
+        .find(|package| Some(package.name) == name)
+    {
+        Some(package) => Ok(Reply::call(credits::invoice(&m, _bot.lang, package))),
+        None => {
+            let mut reply = i18n::text(_bot.lang, "credit_packages").to_string();
+            for package in credits::PACKAGES {
+                let (credits, stars) = (package.credits.to_string(), package.stars.to_string());
+                reply += "\n";
+                reply += &i18n::format(
+                    _bot.lang,
+                    "credit_package",
+                    &[package.name, &credits, &stars],
+                );
+            }
+            return_reply_message(&m, reply)
+        }
+    }
+}
+
+pub async fn credits(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let balance = credits::balance(&_env, &_bot, credits::user_id(&m)).await?;
+    return_reply_message(
+        &m,
+        i18n::format(_bot.lang, "credits_balance", &[&balance.to_string()]),
+    )
+}
+
+pub async fn refund(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let reply = match credits::refund(&m, &_env, &_bot).await? {
+        Some(payment) => i18n::format(
+            _bot.lang,
+            "refunded",
+            &[&payment.stars.to_string(), &payment.credits.to_string()],
+        ),
+        None => i18n::text(_bot.lang, "nothing_to_refund").to_string(),
+    };
+    return_reply_message(&m, reply)
+}
+
+// whether the message mentions the bot or replies to one of its messages
+async fn is_addressed(m: &Message, _env: &Env, _bot: &Bot<'_>) -> Result<bool, WorkerError> {
+    let Some(username) = _bot.username(_env).await? else {
+        return Ok(false);
+    };
+    let replied = m
+        .reply_to_message
+        .as_ref()
+        .and_then(|message| message.from.as_ref())
+        .and_then(|user| user.username.as_deref())
+        .is_some_and(|name| name.eq_ignore_ascii_case(&username));
+    let mention = format!("@{}", username.to_lowercase());
+    let text = m
+        .text
+        .as_ref()
+        .or(m.caption.as_ref())
+        .map(|text| text.to_lowercase())
+        .unwrap_or_default();
+    // `@name` doesn't mention `@name_bot`
+    let mentioned = text.match_indices(&mention).any(|(index, _)| {
+        !text[index + mention.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
     });
-    if let Ok(data) = serde_json::to_string(&body.clone()) {
-        console_log!("{}", data)
+    Ok(replied || mentioned)
+}
+
+/// Whether a message that isn't a command is group chatter the bot doesn't answer, as it gets
+/// every message of the groups it administers.
+pub async fn is_chatter(m: &Message, _env: &Env, _bot: &Bot<'_>) -> Result<bool, WorkerError> {
+    let is_group = matches!(
+        m.chat.kind,
+        ChatType::Group { .. } | ChatType::Supergroup { .. }
+    );
+    Ok(is_group && !is_addressed(m, _env, _bot).await?)
+}
+
+// messages that aren't commands, only the ones addressed to the bot in groups
+pub async fn default_reply(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    if is_chatter(&m, &_env, &_bot).await? {
+        return Ok(Reply::none());
+    }
+    call_chat_api(m, _env, _bot).await
+}
+
+pub async fn call_chat_api(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    if !credits::can_use(&m, &_env, &_bot).await? {
+        return return_reply_message(&m, i18n::text(_bot.lang, "no_credits"));
+    }
+    let (chat_id, message_id) = (m.chat.id, m.message_id);
+    _bot.react(chat_id, message_id, feedback::REACTION_WORKING)
+        .await;
+    // Telegram has no action for listening, recording is the closest to working on a voice
+    let action = match media::voice(&m) {
+        Some(_) => "record_voice",
+        None => "typing",
+    };
+    let reply = chat_reply(m, _env, _bot.clone());
+    let reply = _bot.with_chat_action(chat_id.0, action, reply).await;
+    if reply.is_err() {
+        _bot.react(chat_id, message_id, feedback::REACTION_FAILED)
+            .await;
+    }
+    reply
+}
+
+// the error as the reply, with the status reaction of the message
+fn chat_failed<S: AsRef<str>>(m: &Message, err: S) -> Result<Reply, WorkerError> {
+    Ok(return_reply_message(m, err)?.react(m.chat.id, m.message_id, feedback::REACTION_FAILED))
+}
+
+// transcription, document reading and the completion can each take a while
+async fn chat_reply(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let (key, endpoint) = openai_config(&m, &_env, &_bot).await?;
+    let chat_model = get_chat_model(&m, &_env, &_bot)
+        .await?
+        .unwrap_or(openai::DEFAULT_CHAT_MODEL.to_string());
+    // told after the answer, e.g. that only a part of a long document was read
+    let mut notice = None;
+    let user_msg = if let Some(voice) = media::voice(&m) {
+        let data = _bot.download_file(&voice.file_id).await?;
+        let transcript = match openai::transcribe(
+            &data,
+            &voice.file_name,
+            &voice.mime_type,
+            key.clone(),
+            endpoint.clone(),
+        )
+        .await
+        {
+            Ok(transcript) => transcript,
+            Err(err) => return chat_failed(&m, err.to_string()),
+        };
+        if !get_voice_chat(&m, &_env, &_bot).await? {
+            credits::spend(&m, &_env, &_bot).await?;
+            let reply = return_reply_message(&m, transcript)?;
+            return Ok(reply.react(m.chat.id, m.message_id, feedback::REACTION_DONE));
+        }
+        _bot.send_message(
+            SendMessage::new(ChatTarget::Id(m.chat.id), transcript.as_str()).reply(m.message_id),
+        )
+        .await?;
+        openai::Message::new("user", &transcript)
+    } else if let Some(attachment) = media::document(&m) {
+        let data = _bot.download_file(&attachment.file_id).await?;
+        let text = match document::extract_text(&attachment, &data, _bot.lang) {
+            Ok(text) => text,
+            Err(err) => return chat_failed(&m, err),
+        };
+        let question = message_args(&m);
+        let tokens = _bot
+            .var(&_env, document::VAR_DOCUMENT_TOKENS)
+            .and_then(|tokens| tokens.parse().ok())
+            .unwrap_or(document::DEFAULT_DOCUMENT_TOKENS);
+        let long = document::tokens(&text) > tokens;
+        // every part is a request, then at least one to combine them and one to answer
+        let calls = document::chunk(&text, tokens)
+            .len()
+            .min(document::MAX_CHUNKS) as u64
+            + 2;
+        if long && !credits::can_afford(&m, &_env, &_bot, calls).await? {
+            let calls = calls.to_string();
+            return chat_failed(&m, i18n::format(_bot.lang, "document_credits", &[&calls]));
+        }
+        let content = match long {
+            true => match document::summarize(
+                &attachment.file_name,
+                &text,
+                question.as_deref(),
+                &key,
+                &endpoint,
+                &chat_model,
+                tokens,
+            )
+            .await
+            {
+                Ok(summary) => {
+                    // one credit per request to the model, the answer below takes another one
+                    credits::spend_calls(&m, &_env, &_bot, summary.calls).await?;
+                    if summary.parts > document::MAX_CHUNKS {
+                        let read = document::MAX_CHUNKS.to_string();
+                        let parts = summary.parts.to_string();
+                        let args = [attachment.file_name.as_str(), &read, &parts];
+                        let text = i18n::format(_bot.lang, "document_truncated", &args);
+                        notice = Some(text);
+                    }
+                    summary.text
+                }
+                Err(err) => return chat_failed(&m, err.to_string()),
+            },
+            false => text,
+        };
+        put_chat_document(
+            &m,
+            &_env,
+            &_bot,
+            ChatDocument {
+                file_name: attachment.file_name.clone(),
+                content,
+            },
+        )
+        .await?;
+        let question = question.unwrap_or(format!(
+            "Summarize the document `{}`.",
+            attachment.file_name
+        ));
+        openai::Message::new("user", &question)
+    } else {
+        match (message_args(&m), media::image(&m)) {
+            (Some(prompt), None) => openai::Message::new("user", &prompt),
+            (prompt, Some(image)) => {
+                let data = _bot.download_file(&image.file_id).await?;
+                let mut parts = vec![];
+                if let Some(prompt) = prompt {
+                    parts.push(openai::ContentPart::text(&prompt));
+                }
+                parts.push(openai::ContentPart::image(&image.mime_type, &data));
+                openai::Message::with_parts("user", parts)
+            }
+            (None, None) => return chat_failed(&m, i18n::text(_bot.lang, "invalid_input")),
+        }
+    };
+    let model = match user_msg.has_image() {
+        true => _bot
+            .var(&_env, "OPENAI_VISION_MODEL")
+            .unwrap_or(openai::DEFAULT_VISION_MODEL.to_string()),
+        false => chat_model,
+    };
+    let history = get_chat_history(&m, &_env, &_bot).await?;
+    let mut msgs = build_message_context(&m, history, &_env, _bot.clone()).await?;
+    msgs.push(user_msg);
+    let reply = match openai::chat_completion(&msgs, key, endpoint, &model).await {
+        Ok((reply, usage)) => {
+            add_chat_usage(&m, &_env, &_bot, &usage).await?;
+            reply
+        }
+        Err(err) => return chat_failed(&m, err.to_string()),
+    };
+    let mut history = msgs.clone();
+    history.push(openai::Message::new("assistant", &reply));
+    put_chat_history(&m, &_env, &_bot, history).await?;
+    credits::spend(&m, &_env, &_bot).await?;
+    // sent right away, reactions on it need its id
+    let reply = ChatReply {
+        model,
+        context: msgs,
+        reply,
+        request_message_id: m.message_id.0,
+    };
+    feedback::send_chat_reply(&m, &_env, &_bot, reply).await?;
+    let reply = Reply::none().react(m.chat.id, m.message_id, feedback::REACTION_DONE);
+    Ok(match notice {
+        Some(notice) => reply
+            .send(SendMessage::new(ChatTarget::Id(m.chat.id), notice.as_str()).reply(m.message_id)),
+        None => reply,
+    })
+}
+
+pub fn image_args() -> Parser {
+    Parser::new("image")
+        .option("size", Kind::Text)
+        .option("quality", Kind::Choice(&["standard", "hd"]))
+        .option("n", Kind::Int)
+        .rest("prompt")
+}
+
+fn image_request(args: &Args) -> Result<openai::ImageRequest, String> {
+    let mut request = openai::ImageRequest::new(args.text("prompt").unwrap_or_default());
+    if let Some(size) = args.text("size") {
+        request.size = size.to_string();
+    }
+    request.quality = args.text("quality").map(str::to_string);
+    if let Some(n) = args.int("n") {
+        request.n = u8::try_from(n)
+            .ok()
+            .filter(|n| (1..=10).contains(n))
+            .ok_or("--n should be between 1 and 10")?;
     }
-    Response::from_json(&body)
+    Ok(request)
 }
 
-pub async fn list_env(_m: Message, _env: Env, _bot: Bot<'_>) -> Result<Response, WorkerError> {
-    let mut commands = vec![];
-    for cmd in _bot.commands {
-        commands.push(Command {
-            command: format!("/{}", cmd.0.clone()),
-            description: cmd.0,
+pub async fn image(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let parsed = image_args()
+        .parse_message(&m, _bot.lang)
+        .and_then(|args| image_request(&args));
+    let mut request = match parsed {
+        Ok(request) => request,
+        Err(err) => return return_reply_message(&m, err),
+    };
+    if !credits::can_use(&m, &_env, &_bot).await? {
+        return return_reply_message(&m, i18n::text(_bot.lang, "no_credits"));
+    }
+    if let Some(model) = _bot.var(&_env, "OPENAI_IMAGE_MODEL") {
+        request.model = model;
+    }
+    let (key, endpoint) = openai_config(&m, &_env, &_bot).await?;
+    let generate = openai::generate_images(&request, key, endpoint);
+    let images = match _bot
+        .with_chat_action(m.chat.id.0, "upload_photo", generate)
+        .await
+    {
+        Ok(images) if !images.is_empty() => images,
+        Ok(_) => return return_reply_message(&m, i18n::text(_bot.lang, "no_image_generated")),
+        Err(err) => return return_reply_message(&m, format!("{}", err)),
+    };
+    credits::spend(&m, &_env, &_bot).await?;
+    let captions = images
+        .iter()
+        .map(|image| {
+            image
+                .revised_prompt
+                .as_deref()
+                .unwrap_or(&request.prompt)
+                .chars()
+                .take(MAX_CAPTION_LENGTH)
+                .collect::<String>()
         })
+        .collect::<Vec<String>>();
+
+    let mut history = get_chat_history(&m, &_env, &_bot).await?;
+    history.push(openai::Message::new("user", &request.prompt));
+    history.push(openai::Message::new(
+        "assistant",
+        &format!("[generated {} image(s)] {}", images.len(), captions[0]),
+    ));
+    put_chat_history(&m, &_env, &_bot, history).await?;
+
+    if images.len() == 1 {
+        let mut photo = SendPhoto::new(
+            ChatTarget::Id(m.chat.id),
+            FileToSend::Url(images[0].url.clone()),
+        )
+        .reply(m.message_id);
+        photo.caption = Some(captions[0].clone().into());
+        return Ok(Reply::call(photo));
     }
-    let body = json!({
-        "method": "setMyCommands",
-        "commands": commands,
-    });
-    if let Ok(data) = serde_json::to_string(&body.clone()) {
-        console_log!("{}", data)
+    let media = images
+        .into_iter()
+        .zip(captions)
+        .map(|(image, caption)| InputMediaPhoto {
+            caption: Some(caption),
+            ..InputMediaPhoto::new(FileToSend::Url(image.url))
+        })
+        .collect();
+    Ok(Reply::call(SendMediaGroup {
+        chat_id: ChatTarget::Id(m.chat.id),
+        media,
+        reply_to_message_id: Some(m.message_id),
+    }))
+}
+
+pub async fn sync_commands(m: Message, _env: Env, _bot: Bot<'_>) -> Result<Reply, WorkerError> {
+    let is_admin = m
+        .from
+        .as_ref()
+        .is_some_and(|user| profile::is_bot_admin(&_bot, &_env, user.id.0));
+    if !is_admin {
+        return return_reply_message(&m, i18n::text(_bot.lang, "bot_admins_only"));
     }
-    Response::from_json(&body)
+    let report = profile::sync(&_bot, &_env).await;
+    console_log!("{:?}", report);
+    return_reply_message(&m, report.join("\n"))
+}
+
+#[test]
+fn test_parse() {
+    let parse = |text| image_request(&image_args().parse(text, Lang::En)?);
+    let request = parse("--size 1792x1024 --n 2 a red fox").unwrap();
+    assert_eq!(request.size, "1792x1024");
+    assert_eq!(request.n, 2);
+    assert_eq!(request.quality, None);
+    assert_eq!(request.prompt, "a red fox");
+    assert!(parse("--n 20 a red fox").is_err());
+    assert!(parse("--quality").is_err());
+    assert!(parse("").is_err());
+}
+
+#[test]
+fn test_mask_key() {
+    assert_eq!(mask_key("sk-proj-abcdefgh1234"), "sk-…1234");
+    assert_eq!(mask_key("short"), "…");
 }
@@ -0,0 +1,420 @@
+//! Credits for the models bought with Telegram Stars, used by chats not in `ALLOWED_CHATS`.
+use serde::{Deserialize, Serialize};
+use telegram_types::bot::methods::ChatTarget;
+use telegram_types::bot::types::{Message, User};
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::console_log;
+use crate::i18n::{self, Lang};
+use crate::methods::{AnswerPreCheckoutQuery, LabeledPrice, RefundStarPayment, SendInvoice};
+use crate::middleware::is_allowed;
+use crate::platform::Env;
+use crate::reminder;
+
+/// Set to let chats outside `ALLOWED_CHATS` use the bot with bought credits.
+const VAR_PAID_ACCESS: &str = "PAID_ACCESS";
+/// Telegram Stars.
+const CURRENCY: &str = "XTR";
+const PAYLOAD_PREFIX: &str = "credits:";
+
+pub struct Package {
+    pub name: &'static str,
+    /// Messages to the models the package pays for.
+    pub credits: u64,
+    pub stars: u64,
+}
+
+pub const PACKAGES: &[Package] = &[
+    Package {
+        name: "100",
+        credits: 100,
+        stars: 50,
+    },
+    Package {
+        name: "500",
+        credits: 500,
+        stars: 200,
+    },
+];
+pub const PACKAGE_NAMES: &[&str] = &["100", "500"];
+
+fn package(payload: &str) -> Option<&'static Package> {
+    let name = payload.strip_prefix(PAYLOAD_PREFIX)?;
+    PACKAGES.iter().find(|package| package.name == name)
+}
+
+/// Asks the bot to confirm a payment before it is made, `telegram_types` doesn't know it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PreCheckoutQuery {
+    pub id: String,
+    pub from: User,
+    pub currency: String,
+    pub total_amount: u64,
+    pub invoice_payload: String,
+}
+
+/// Field of the service message sent once a payment is made.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SuccessfulPayment {
+    pub currency: String,
+    pub total_amount: u64,
+    pub invoice_payload: String,
+    pub telegram_payment_charge_id: String,
+}
+
+/// A purchase, kept for refunds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub charge_id: String,
+    pub credits: u64,
+    pub stars: u64,
+    pub refunded: bool,
+    /// Recorded before its credits are added, see `handle_payment`.
+    #[serde(default)]
+    pub pending: bool,
+}
+
+pub fn paid_access(bot: &Bot<'_>, env: &Env) -> bool {
+    bot.var(env, VAR_PAID_ACCESS).is_some()
+}
+
+pub fn invoice(m: &Message, lang: Lang, package: &Package) -> SendInvoice<'static> {
+    let credits = package.credits.to_string();
+    SendInvoice {
+        chat_id: ChatTarget::Id(m.chat.id),
+        title: i18n::format(lang, "invoice_title", &[&credits]),
+        description: i18n::format(lang, "invoice_description", &[&credits]),
+        payload: format!("{}{}", PAYLOAD_PREFIX, package.name),
+        currency: CURRENCY.to_string(),
+        prices: vec![LabeledPrice {
+            label: i18n::format(lang, "invoice_title", &[&credits]),
+            amount: package.stars,
+        }],
+    }
+}
+
+// invoices of packages that changed since they were sent are refused
+fn check_pre_checkout(query: &PreCheckoutQuery) -> bool {
+    package(&query.invoice_payload)
+        .is_some_and(|package| query.currency == CURRENCY && query.total_amount == package.stars)
+}
+
+/// Answer to a pre-checkout query, which has to come within 10 seconds.
+pub fn answer_pre_checkout(query: &PreCheckoutQuery) -> AnswerPreCheckoutQuery {
+    let lang = query
+        .from
+        .language_code
+        .as_deref()
+        .and_then(Lang::from_code)
+        .unwrap_or_default();
+    match check_pre_checkout(query) {
+        true => AnswerPreCheckoutQuery {
+            pre_checkout_query_id: query.id.clone(),
+            ok: true,
+            error_message: None,
+        },
+        false => AnswerPreCheckoutQuery {
+            pre_checkout_query_id: query.id.clone(),
+            ok: false,
+            error_message: Some(i18n::text(lang, "invoice_outdated").to_string()),
+        },
+    }
+}
+
+fn balance_key(user_id: i64) -> String {
+    format!("INDEX_CREDITS:{}", user_id)
+}
+
+fn payment_prefix(user_id: i64) -> String {
+    format!("INDEX_PAYMENT:{}:", user_id)
+}
+
+/// The user who pays, credits belong to users and not to chats.
+pub fn user_id(m: &Message) -> i64 {
+    m.from.as_ref().map_or(m.chat.id.0, |user| user.id.0)
+}
+
+pub async fn balance(env: &Env, bot: &Bot<'_>, user_id: i64) -> Result<u64, WorkerError> {
+    let balance = bot.store(env)?.get(&balance_key(user_id)).text().await?;
+    Ok(balance
+        .and_then(|balance| balance.parse().ok())
+        .unwrap_or_default())
+}
+
+// KV isn't atomic, concurrent updates of one user may get lost
+async fn add(env: &Env, bot: &Bot<'_>, user_id: i64, credits: i64) -> Result<u64, WorkerError> {
+    let balance = balance(env, bot, user_id).await?;
+    let balance = balance.saturating_add_signed(credits);
+    bot.store(env)?
+        .put(&balance_key(user_id), balance.to_string())?
+        .execute()
+        .await?;
+    Ok(balance)
+}
+
+/// Whether the sender may use the models, free in allowed chats.
+pub async fn can_use(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<bool, WorkerError> {
+    can_afford(m, env, bot, 1).await
+}
+
+/// Whether the sender has the credits for `calls` model calls, free in allowed chats.
+pub async fn can_afford(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    calls: u64,
+) -> Result<bool, WorkerError> {
+    if is_allowed(bot, env, m.chat.id.0) {
+        return Ok(true);
+    }
+    Ok(balance(env, bot, user_id(m)).await? >= calls)
+}
+
+/// Take a credit from the sender for a model call, unless the chat is allowed.
+pub async fn spend(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<(), WorkerError> {
+    spend_calls(m, env, bot, 1).await
+}
+
+/// Take a credit from the sender for each of `calls` model calls, unless the chat is allowed.
+pub async fn spend_calls(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    calls: u64,
+) -> Result<(), WorkerError> {
+    if !is_allowed(bot, env, m.chat.id.0) {
+        add(env, bot, user_id(m), -(calls as i64)).await?;
+    }
+    Ok(())
+}
+
+/// Credit a successful payment, replying with the new balance.
+///
+/// Telegram redelivers the update when this fails, so the payment is recorded as pending before
+/// its credits are added, and one credited before is found by its charge id and not credited again.
+pub async fn handle_payment(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    payment: SuccessfulPayment,
+) -> Result<String, WorkerError> {
+    let lang = i18n::language(m, env, bot).await?;
+    let Some(package) = package(&payment.invoice_payload) else {
+        return Err(WorkerError::RustError(format!(
+            "Payment {} is for an unknown package",
+            payment.telegram_payment_charge_id
+        )));
+    };
+    let user_id = user_id(m);
+    let credits = package.credits.to_string();
+    let store = bot.store(env)?;
+    let suffix = format!(":{}", payment.telegram_payment_charge_id);
+    let key = store
+        .list(&payment_prefix(user_id))
+        .await?
+        .into_iter()
+        .find(|key| key.ends_with(&suffix));
+    let key = match key {
+        Some(key) => {
+            let record = store.get(&key).json::<Payment>().await?;
+            if record.is_some_and(|record| !record.pending) {
+                console_log!(
+                    "Payment {} was credited already, ignoring...",
+                    payment.telegram_payment_charge_id
+                );
+                let balance = balance(env, bot, user_id).await?.to_string();
+                return Ok(i18n::format(lang, "credits_added", &[&credits, &balance]));
+            }
+            key
+        }
+        // sorted by time, so refunds start with the last purchase
+        None => format!(
+            "{}{:012}{}",
+            payment_prefix(user_id),
+            reminder::now(),
+            suffix
+        ),
+    };
+    console_log!(
+        "User {} paid {} {} for {} credits",
+        user_id,
+        payment.total_amount,
+        payment.currency,
+        package.credits
+    );
+    let record = Payment {
+        charge_id: payment.telegram_payment_charge_id,
+        credits: package.credits,
+        stars: payment.total_amount,
+        refunded: false,
+        pending: true,
+    };
+    store.put(&key, &record)?.execute().await?;
+    let balance = add(env, bot, user_id, package.credits as i64).await?;
+    let record = Payment {
+        pending: false,
+        ..record
+    };
+    store.put(&key, &record)?.execute().await?;
+    Ok(i18n::format(
+        lang,
+        "credits_added",
+        &[&credits, &balance.to_string()],
+    ))
+}
+
+/// Refund the last purchase of the sender whose credits are still unused.
+pub async fn refund(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<Option<Payment>, WorkerError> {
+    let user_id = user_id(m);
+    let balance = balance(env, bot, user_id).await?;
+    let store = bot.store(env)?;
+    let keys = store.list(&payment_prefix(user_id)).await?;
+    for key in keys.iter().rev() {
+        let Some(payment) = store.get(key).json::<Payment>().await? else {
+            continue;
+        };
+        if payment.refunded || payment.pending || payment.credits > balance {
+            continue;
+        }
+        bot.call_method(RefundStarPayment {
+            user_id,
+            telegram_payment_charge_id: payment.charge_id.clone(),
+        })
+        .await?;
+        let payment = Payment {
+            refunded: true,
+            ..payment
+        };
+        store.put(key, &payment)?.execute().await?;
+        add(env, bot, user_id, -(payment.credits as i64)).await?;
+        return Ok(Some(payment));
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_pre_checkout() {
+    let query = |currency: &str, total_amount, invoice_payload: &str| {
+        serde_json::from_value::<PreCheckoutQuery>(serde_json::json!({
+            "id": "1",
+            "from": {"id": 1, "is_bot": false, "first_name": "Kevin", "language_code": "zh-hans"},
+            "currency": currency,
+            "total_amount": total_amount,
+            "invoice_payload": invoice_payload,
+        }))
+        .unwrap()
+    };
+    assert!(answer_pre_checkout(&query("XTR", 50, "credits:100")).ok);
+    let answer = answer_pre_checkout(&query("XTR", 10, "credits:100"));
+    assert!(!answer.ok);
+    assert_eq!(
+        answer.error_message.as_deref(),
+        Some(i18n::text(Lang::Zh, "invoice_outdated"))
+    );
+    assert!(!answer_pre_checkout(&query("XTR", 50, "credits:7")).ok);
+    assert!(!answer_pre_checkout(&query("USD", 50, "credits:100")).ok);
+    assert!(PACKAGES
+        .iter()
+        .map(|package| package.name)
+        .eq(PACKAGE_NAMES.iter().copied()));
+}
+
+/// A Bot API stand-in on a local port answering every request with `true`, the request lines
+/// and bodies it received are sent to the returned channel.
+#[cfg(test)]
+fn stand_in() -> (String, std::sync::mpsc::Receiver<String>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let api_base = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                match header.trim().split_once(':') {
+                    Some((name, value)) if name.eq_ignore_ascii_case("content-length") => {
+                        length = value.trim().parse().unwrap()
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            let _ = sender.send(format!("{}{}", request_line.trim(), body));
+            let response = r#"{"ok":true,"result":true}"#;
+            let _ = write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.len(),
+                response
+            );
+        }
+    });
+    (api_base, receiver)
+}
+
+#[test]
+fn test_payment() {
+    use futures::executor::block_on;
+
+    use crate::bot::VAR_API_BASE;
+    use crate::{TELEGRAM_API_TOKEN, VAR_KV_STORE};
+
+    let (api_base, requests) = stand_in();
+    let vars = [
+        (TELEGRAM_API_TOKEN, "1:token"),
+        (VAR_KV_STORE, "KV_STORE"),
+        (VAR_API_BASE, api_base.as_str()),
+    ]
+    .map(|(name, value)| (name.to_string(), value.to_string()));
+    let env = Env::local(vars.into(), None);
+    let bot = Bot::new_with_env(&env, "", TELEGRAM_API_TOKEN, VAR_KV_STORE).unwrap();
+    let m = serde_json::from_value::<Message>(serde_json::json!({
+        "message_id": 1,
+        "date": 1700000000,
+        "chat": {"id": 7, "type": "private", "first_name": "Kevin"},
+        "from": {"id": 7, "is_bot": false, "first_name": "Kevin"},
+    }))
+    .unwrap();
+    let payment = |charge_id: &str| SuccessfulPayment {
+        currency: CURRENCY.to_string(),
+        total_amount: 50,
+        invoice_payload: "credits:100".to_string(),
+        telegram_payment_charge_id: charge_id.to_string(),
+    };
+
+    block_on(handle_payment(&m, &env, &bot, payment("a"))).unwrap();
+    assert_eq!(block_on(balance(&env, &bot, 7)).unwrap(), 100);
+    // a redelivered payment is credited once
+    block_on(handle_payment(&m, &env, &bot, payment("a"))).unwrap();
+    assert_eq!(block_on(balance(&env, &bot, 7)).unwrap(), 100);
+    block_on(handle_payment(&m, &env, &bot, payment("b"))).unwrap();
+    assert_eq!(block_on(balance(&env, &bot, 7)).unwrap(), 200);
+    let unknown = SuccessfulPayment {
+        invoice_payload: "credits:7".to_string(),
+        ..payment("c")
+    };
+    assert!(block_on(handle_payment(&m, &env, &bot, unknown)).is_err());
+
+    // the last purchase is refunded first, and only while its credits are unused
+    block_on(add(&env, &bot, 7, -150)).unwrap();
+    assert_eq!(block_on(refund(&m, &env, &bot)).unwrap(), None);
+    block_on(add(&env, &bot, 7, 50)).unwrap();
+    let refunded = block_on(refund(&m, &env, &bot)).unwrap().unwrap();
+    assert_eq!(refunded.charge_id, "b");
+    assert!(refunded.refunded);
+    assert_eq!(block_on(balance(&env, &bot, 7)).unwrap(), 0);
+    let request = requests.try_recv().unwrap();
+    assert!(request.starts_with("POST /bot1:token/refundStarPayment "));
+    assert!(request.contains(r#""telegram_payment_charge_id":"b""#));
+    assert!(requests.try_recv().is_err());
+}
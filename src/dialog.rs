@@ -0,0 +1,79 @@
+//! Multi-step conversations: while a user is in a dialog, their next message goes to it.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use telegram_types::bot::types::Message;
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::platform::Env;
+
+/// Seconds a dialog waits for the next message before it is dropped.
+const DIALOG_TTL: u64 = 10 * 60;
+
+/// Where a user is in a dialog, with the answers collected so far.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Dialog {
+    /// Name the dialog is registered under with `Bot::register_dialog`.
+    pub name: String,
+    pub step: String,
+    #[serde(default)]
+    pub data: HashMap<String, String>,
+}
+
+impl Dialog {
+    pub fn new<S: AsRef<str>>(name: S, step: S) -> Self {
+        Self {
+            name: name.as_ref().to_string(),
+            step: step.as_ref().to_string(),
+            data: HashMap::new(),
+        }
+    }
+
+    pub fn step<S: AsRef<str>>(mut self, step: S) -> Self {
+        self.step = step.as_ref().to_string();
+        self
+    }
+
+    pub fn with<S: AsRef<str>>(mut self, key: S, value: S) -> Self {
+        self.data
+            .insert(key.as_ref().to_string(), value.as_ref().to_string());
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.data.get(key).map(String::as_str)
+    }
+}
+
+// dialogs are per user, several members of a group can be in one at the same time
+fn dialog_key(m: &Message) -> String {
+    let user_id = m.from.as_ref().map_or(m.chat.id.0, |user| user.id.0);
+    format!("INDEX_DIALOG:{}:{}", m.chat.id.0, user_id)
+}
+
+/// Start or advance the dialog of the message sender.
+pub async fn save(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    dialog: &Dialog,
+) -> Result<(), WorkerError> {
+    // a `HashMap` would turn into an empty JS `Map` when stored directly
+    let dialog = serde_json::to_string(dialog)?;
+    bot.store(env)?
+        .put(&dialog_key(m), dialog)?
+        .expiration_ttl(DIALOG_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn load(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<Option<Dialog>, WorkerError> {
+    bot.store(env)?.get(&dialog_key(m)).json::<Dialog>().await
+}
+
+pub async fn finish(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<(), WorkerError> {
+    bot.store(env)?.delete(&dialog_key(m)).await?;
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! Text extraction and summarisation of uploaded documents.
+use std::io::{Cursor, Read};
+
+use worker::Error as WorkerError;
+
+use crate::{
+    i18n::{self, Lang},
+    media::Attachment,
+    openai,
+};
+
+/// Estimated tokens of document text sent to the model in one request, and kept in the chat
+/// context afterwards.
+pub const VAR_DOCUMENT_TOKENS: &str = "DOCUMENT_TOKENS";
+/// Leaves room for the prompt, the history and the answer in a 4k context.
+pub const DEFAULT_DOCUMENT_TOKENS: usize = 1500;
+/// Upper bound of map requests for a single document, later parts are left out.
+pub const MAX_CHUNKS: usize = 8;
+/// Upper bound of intermediate reduce passes.
+const MAX_REDUCE_ROUNDS: usize = 3;
+
+const DOCX_MIME_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+enum Format {
+    Text,
+    Pdf,
+    Docx,
+}
+
+fn format(document: &Attachment) -> Option<Format> {
+    let extension = document
+        .file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match (extension.as_str(), document.mime_type.as_str()) {
+        ("txt" | "md" | "markdown", _) => Some(Format::Text),
+        ("pdf", _) | (_, "application/pdf") => Some(Format::Pdf),
+        ("docx", _) | (_, DOCX_MIME_TYPE) => Some(Format::Docx),
+        (_, mime_type) if mime_type.starts_with("text/") => Some(Format::Text),
+        _ => None,
+    }
+}
+
+pub fn extract_text(document: &Attachment, data: &[u8], lang: Lang) -> Result<String, String> {
+    let text = match format(document) {
+        Some(Format::Text) => String::from_utf8_lossy(data).to_string(),
+        Some(Format::Pdf) => {
+            let pdf = lopdf::Document::load_mem(data).map_err(|err| err.to_string())?;
+            let pages = pdf.get_pages().into_keys().collect::<Vec<u32>>();
+            pdf.extract_text(&pages).map_err(|err| err.to_string())?
+        }
+        Some(Format::Docx) => {
+            let mut archive =
+                zip::ZipArchive::new(Cursor::new(data)).map_err(|err| err.to_string())?;
+            let mut xml = String::new();
+            archive
+                .by_name("word/document.xml")
+                .map_err(|err| err.to_string())?
+                .read_to_string(&mut xml)
+                .map_err(|err| err.to_string())?;
+            docx_text(&xml)
+        }
+        None => {
+            return Err(i18n::format(
+                lang,
+                "unsupported_document",
+                &[&document.file_name],
+            ))
+        }
+    };
+    let text = text.trim().to_string();
+    match text.is_empty() {
+        true => Err(i18n::format(
+            lang,
+            "no_text_in_document",
+            &[&document.file_name],
+        )),
+        false => Ok(text),
+    }
+}
+
+// text runs of `word/document.xml`, one line per paragraph
+fn docx_text(xml: &str) -> String {
+    let mut text = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        rest = &rest[start + end + 1..];
+        let name = tag.split_whitespace().next().unwrap_or_default();
+        match name {
+            "w:t" => {
+                let close = rest.find("</w:t>").unwrap_or(rest.len());
+                text.push_str(&unescape_xml(&rest[..close]));
+                rest = &rest[close..];
+            }
+            "w:tab/" => text.push('\t'),
+            "w:br/" | "w:cr/" | "/w:p" => text.push('\n'),
+            _ => {}
+        }
+    }
+    text
+}
+
+fn unescape_xml(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+// quarters of a token: about four ASCII characters make a token, a CJK character one or more
+fn weight(c: char) -> usize {
+    match c.is_ascii() {
+        true => 1,
+        false => 4,
+    }
+}
+
+/// Estimated tokens of `text`, without the tokenizer of the model.
+pub fn tokens(text: &str) -> usize {
+    text.chars().map(weight).sum::<usize>().div_ceil(4)
+}
+
+/// The start of `text` that takes at most `tokens` estimated tokens.
+pub fn truncate(text: &str, tokens: usize) -> String {
+    let mut budget = tokens * 4;
+    text.chars()
+        .take_while(|&c| match budget.checked_sub(weight(c)) {
+            Some(rest) => {
+                budget = rest;
+                true
+            }
+            None => false,
+        })
+        .collect()
+}
+
+/// Split text into chunks of at most `tokens` estimated tokens, preferring paragraph boundaries.
+pub fn chunk(text: &str, tokens: usize) -> Vec<String> {
+    let size = tokens * 4;
+    let mut chunks = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+    for paragraph in text.split("\n\n").filter(|p| !p.trim().is_empty()) {
+        let paragraph_len = paragraph.chars().map(weight).sum::<usize>();
+        if current_len > 0 && current_len + paragraph_len + 2 > size {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        if paragraph_len > size {
+            let mut piece = String::new();
+            let mut piece_len = 0;
+            for c in paragraph.chars() {
+                if piece_len + weight(c) > size {
+                    chunks.push(std::mem::take(&mut piece));
+                    piece_len = 0;
+                }
+                piece.push(c);
+                piece_len += weight(c);
+            }
+            chunks.push(piece);
+            continue;
+        }
+        if current_len > 0 {
+            current.push_str("\n\n");
+            current_len += 2;
+        }
+        current.push_str(paragraph);
+        current_len += paragraph_len;
+    }
+    if current_len > 0 {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// A document summarised by `summarize`.
+pub struct Summary {
+    pub text: String,
+    /// Parts the document had, only the first `MAX_CHUNKS` of them are read.
+    pub parts: usize,
+    /// Requests made to the model, each one costs a credit.
+    pub calls: u64,
+}
+
+/// Map-reduce summary of a document too long to fit into the model context.
+///
+/// Every chunk of `tokens` estimated tokens is summarised on its own, keeping details
+/// relevant to the question, then the partial summaries are combined until they fit into a
+/// single chunk.
+///
+/// The requests run one after another within the update, so a long document can take
+/// longer than the Worker is allowed to run and fail without an answer.
+pub async fn summarize(
+    file_name: &str,
+    text: &str,
+    question: Option<&str>,
+    key: &str,
+    endpoint: &Option<String>,
+    model: &str,
+    tokens: usize,
+) -> Result<Summary, WorkerError> {
+    let focus = match question {
+        Some(question) => format!(" Keep every detail relevant to: {}", question),
+        None => String::new(),
+    };
+    let complete = |instruction: String, text: String| async move {
+        let msgs = vec![
+            openai::Message::new("system", &instruction),
+            openai::Message::new("user", &text),
+        ];
+        openai::call_chat_api(&msgs, key.to_string(), endpoint.clone(), model).await
+    };
+    let mut calls = 0;
+    let mut chunks = chunk(text, tokens);
+    let parts = chunks.len();
+    chunks.truncate(MAX_CHUNKS);
+    let mut summaries = vec![];
+    for (index, part) in chunks.iter().enumerate() {
+        let prompt = format!(
+            "Summarize part {}/{} of the document `{}`.{}",
+            index + 1,
+            chunks.len(),
+            file_name,
+            focus
+        );
+        summaries.push(complete(prompt, part.clone()).await?);
+        calls += 1;
+    }
+    let prompt = format!(
+        "Combine these partial summaries of the document `{}` into one summary.{}",
+        file_name, focus
+    );
+    for _ in 0..MAX_REDUCE_ROUNDS {
+        let parts = chunk(&summaries.join("\n\n"), tokens);
+        if parts.len() <= 1 {
+            break;
+        }
+        summaries = vec![];
+        for part in parts {
+            summaries.push(complete(prompt.clone(), part).await?);
+            calls += 1;
+        }
+    }
+    let combined = truncate(&summaries.join("\n\n"), tokens);
+    // the summary goes into the context of later questions, so it has to fit as well
+    let text = truncate(&complete(prompt, combined).await?, tokens);
+    Ok(Summary {
+        text,
+        parts,
+        calls: calls + 1,
+    })
+}
+
+#[test]
+fn test_docx_text_and_chunk() {
+    let xml = r#"<w:body><w:p><w:r><w:t xml:space="preserve">Tom &amp; Jerry</w:t></w:r><w:r><w:tab/><w:t>&#x4F60;&#22909;</w:t></w:r></w:p><w:p><w:r><w:t>bye</w:t></w:r></w:p></w:body>"#;
+    assert_eq!(docx_text(xml), "Tom & Jerry\t你好\nbye\n");
+    assert_eq!(
+        chunk("aaaa\n\nbb\n\ncc\n\ndddddddddd", 2),
+        vec!["aaaa\n\nbb", "cc", "dddddddd", "dd"]
+    );
+    assert_eq!(chunk("你好世界", 2), vec!["你好", "世界"]);
+    assert_eq!(tokens("abcde你好"), 4);
+    assert_eq!(truncate("ab你好", 2), "ab你");
+}
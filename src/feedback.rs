@@ -0,0 +1,226 @@
+//! Reactions on the answers of the chat model: 👎 asks for another answer, 👍 and 👎 are kept
+//! per model and prompt for review.
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use telegram_types::bot::methods::{ChatTarget, SendMessage};
+use telegram_types::bot::types::{Chat, Message, MessageId, User};
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::chat::{add_chat_usage, get_chat_history, put_chat_history};
+use crate::command::openai_config;
+use crate::console_log;
+use crate::credits;
+use crate::middleware;
+use crate::openai;
+use crate::platform::Env;
+
+/// Reaction on a message the bot is working on.
+pub const REACTION_WORKING: &str = "👀";
+// ✅ and ❌ aren't among the reactions bots may set
+pub const REACTION_DONE: &str = "👌";
+pub const REACTION_FAILED: &str = "🤷";
+const REACTION_GOOD: &str = "👍";
+const REACTION_BAD: &str = "👎";
+/// Seconds an answer can be rated or regenerated after it was sent.
+const CHAT_REPLY_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// A change of the reactions of a user on a message, `telegram_types` doesn't know it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MessageReactionUpdated {
+    pub chat: Chat,
+    pub message_id: MessageId,
+    /// Unset for anonymous reactions.
+    pub user: Option<User>,
+    pub date: i64,
+    pub old_reaction: Vec<ReactionType>,
+    pub new_reaction: Vec<ReactionType>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReactionType {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Unset for custom emoji and paid reactions.
+    pub emoji: Option<String>,
+}
+
+impl MessageReactionUpdated {
+    fn has(reactions: &[ReactionType], emoji: &str) -> bool {
+        reactions
+            .iter()
+            .any(|reaction| reaction.emoji.as_deref() == Some(emoji))
+    }
+
+    fn added(&self, emoji: &str) -> bool {
+        Self::has(&self.new_reaction, emoji) && !Self::has(&self.old_reaction, emoji)
+    }
+
+    // the reacted message from the user who reacted, only its chat and id are known
+    fn message(&self) -> Result<Message, WorkerError> {
+        let message = json!({
+            "message_id": self.message_id,
+            "date": self.date,
+            "chat": self.chat,
+            "from": self.user,
+        });
+        Ok(serde_json::from_value(message)?)
+    }
+}
+
+/// An answer of the chat model, with what it was asked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatReply {
+    pub model: String,
+    /// Messages the model answered to, images left out.
+    pub context: Vec<openai::Message>,
+    pub reply: String,
+    /// Message the answer replies to.
+    pub request_message_id: i64,
+}
+
+impl ChatReply {
+    fn prompt(&self) -> String {
+        self.context
+            .iter()
+            .rev()
+            .find(|msg| msg.role == "user")
+            .map(|msg| msg.content.text())
+            .unwrap_or_default()
+    }
+}
+
+/// A rating of an answer, stored under `INDEX_FEEDBACK:{model}:` for review.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Feedback {
+    pub model: String,
+    pub prompt: String,
+    pub reply: String,
+    pub good: bool,
+}
+
+fn chat_reply_key(chat_id: i64, message_id: i64) -> String {
+    format!("INDEX_CHAT_REPLY:{}:{}", chat_id, message_id)
+}
+
+/// Send an answer of the chat model, remembering it for reactions.
+pub async fn send_chat_reply(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    reply: ChatReply,
+) -> Result<(), WorkerError> {
+    let mut message = SendMessage::new(ChatTarget::Id(m.chat.id), reply.reply.as_str());
+    if reply.request_message_id != m.message_id.0 {
+        message = message.reply(MessageId(reply.request_message_id));
+    }
+    let sent = bot.send_message(message).await?;
+    let reply = ChatReply {
+        context: reply
+            .context
+            .into_iter()
+            .map(openai::Message::without_images)
+            .collect(),
+        ..reply
+    };
+    bot.store(env)?
+        .put(&chat_reply_key(m.chat.id.0, sent.message_id.0), reply)?
+        .expiration_ttl(CHAT_REPLY_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Record the rating of an answer and regenerate it on 👎.
+pub async fn handle_reaction(
+    reaction: MessageReactionUpdated,
+    env: &Env,
+    bot: &Bot<'_>,
+) -> Result<(), WorkerError> {
+    let store = bot.store(env)?;
+    let key = chat_reply_key(reaction.chat.id.0, reaction.message_id.0);
+    let Some(reply) = store.get(&key).json::<ChatReply>().await? else {
+        console_log!("Reaction on a message that isn't an answer, ignoring...");
+        return Ok(());
+    };
+    let user_id = reaction.user.as_ref().map_or(0, |user| user.id.0);
+    let feedback_key = format!(
+        "INDEX_FEEDBACK:{}:{}:{}:{}",
+        reply.model, reaction.chat.id.0, reaction.message_id.0, user_id
+    );
+    let good = MessageReactionUpdated::has(&reaction.new_reaction, REACTION_GOOD);
+    let bad = MessageReactionUpdated::has(&reaction.new_reaction, REACTION_BAD);
+    if good || bad {
+        let feedback = Feedback {
+            model: reply.model.clone(),
+            prompt: reply.prompt(),
+            reply: reply.reply.clone(),
+            good,
+        };
+        store.put(&feedback_key, feedback)?.execute().await?;
+    } else {
+        store.delete(&feedback_key).await?;
+    }
+    if reaction.added(REACTION_BAD) {
+        let m = reaction.message()?;
+        // a new answer costs the same as a message
+        if !credits::can_use(&m, env, bot).await? {
+            console_log!("No credits to regenerate an answer, ignoring...");
+            return Ok(());
+        }
+        if let Some(Ordering::Equal | Ordering::Greater) =
+            middleware::count_message(bot, env, m.chat.id.0).await?
+        {
+            console_log!("Rate limit reached, not regenerating...");
+            return Ok(());
+        }
+        regenerate(&m, env, bot, reply).await?;
+        credits::spend(&m, env, bot).await?;
+    }
+    Ok(())
+}
+
+async fn regenerate(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    reply: ChatReply,
+) -> Result<(), WorkerError> {
+    let (key, endpoint) = openai_config(m, env, bot).await?;
+    let answer = openai::chat_completion(&reply.context, key, endpoint, &reply.model);
+    let (answer, usage) = bot.with_chat_action(m.chat.id.0, "typing", answer).await?;
+    add_chat_usage(m, env, bot, &usage).await?;
+    // the new answer replaces the disliked one when it is still the last one
+    let mut history = get_chat_history(m, env, bot).await?;
+    if let Some(last) = history.last_mut() {
+        if last.role == "assistant" && last.content.text() == reply.reply {
+            *last = openai::Message::new("assistant", &answer);
+            put_chat_history(m, env, bot, history).await?;
+        }
+    }
+    let reply = ChatReply {
+        reply: answer,
+        ..reply
+    };
+    send_chat_reply(m, env, bot, reply).await
+}
+
+#[test]
+fn test_reaction_update() {
+    let reaction = serde_json::from_value::<MessageReactionUpdated>(json!({
+        "chat": {"id": 1, "type": "private", "first_name": "Kevin"},
+        "message_id": 42,
+        "user": {"id": 7, "is_bot": false, "first_name": "Kevin"},
+        "date": 1700000000,
+        "old_reaction": [{"type": "emoji", "emoji": "👍"}],
+        "new_reaction": [{"type": "emoji", "emoji": "👎"}, {"type": "custom_emoji", "custom_emoji_id": "1"}],
+    }))
+    .unwrap();
+    assert!(reaction.added(REACTION_BAD));
+    assert!(!reaction.added(REACTION_GOOD));
+    let message = reaction.message().unwrap();
+    assert_eq!((message.chat.id.0, message.message_id.0), (1, 42));
+    assert_eq!(credits::user_id(&message), 7);
+}
@@ -0,0 +1,529 @@
+//! Catalog of the replies of the bot in the languages it speaks.
+use telegram_types::bot::types::Message;
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::platform::Env;
+
+/// Language of chats without a setting or users Telegram tells no supported language of.
+const VAR_LANGUAGE: &str = "LANGUAGE";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Lang {
+    pub const ALL: &'static [Lang] = &[Lang::En, Lang::Zh];
+
+    /// Language code as used by Telegram and `CommandInfo::translation`.
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Zh => "zh",
+        }
+    }
+
+    /// `zh`, `zh-hans` and `zh-TW` are all Chinese, `None` for languages without a catalog.
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.split(['-', '_']).next().unwrap_or_default();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|lang| lang.code().eq_ignore_ascii_case(code))
+    }
+}
+
+// key, English, Chinese; `{}` are filled in order by `format`
+const CATALOG: &[(&str, &str, &str)] = &[
+    ("success", "Success", "成功"),
+    ("usage", "{}\nUsage: {}", "{}\n用法：{}"),
+    ("unknown_option", "Unknown option --{}", "未知选项 --{}"),
+    (
+        "option_without_value",
+        "--{} takes no value",
+        "--{} 不接受参数值",
+    ),
+    (
+        "missing_option_value",
+        "Missing value for --{}",
+        "--{} 缺少参数值",
+    ),
+    (
+        "unexpected_argument",
+        "Unexpected argument: {}",
+        "多余的参数：{}",
+    ),
+    ("missing_argument", "Missing argument <{}>", "缺少参数 <{}>"),
+    ("unclosed_quote", "Unclosed quote {}", "引号 {} 未闭合"),
+    ("not_number", "`{}` is not a number", "`{}` 不是数字"),
+    ("not_url", "`{}` is not a URL", "`{}` 不是网址"),
+    (
+        "not_duration",
+        "`{}` is not a duration like 90s, 5m or 1h30m",
+        "`{}` 不是时长，例如 90s、5m 或 1h30m",
+    ),
+    (
+        "not_choice",
+        "`{}` should be one of {}",
+        "`{}` 应为 {} 之一",
+    ),
+    ("unknown_command", "Unknown command /{}.", "未知命令 /{}。"),
+    ("did_you_mean", " Did you mean /{}?", "你是想用 /{} 吗？"),
+    (
+        "send_help",
+        " Send /help for the list of commands.",
+        "发送 /help 查看命令列表。",
+    ),
+    ("help_title", "Available commands:", "可用命令："),
+    ("help_aliases", "Aliases: /{}", "别名：/{}"),
+    ("help_usage", "Usage: {}", "用法：{}"),
+    ("help_examples", "Examples:", "示例："),
+    (
+        "help_details",
+        "Send /help <command> for details.",
+        "发送 /help <命令> 查看详情。",
+    ),
+    ("General", "General", "通用"),
+    ("Chat", "Chat", "聊天"),
+    ("Settings", "Settings", "设置"),
+    ("Reminders", "Reminders", "提醒"),
+    (
+        "ask_system_prompt",
+        "Send the system prompt for this chat, or /cancel.",
+        "请发送这个聊天的系统提示词，或 /cancel 取消。",
+    ),
+    (
+        "ask_system_prompt_text",
+        "Send the system prompt as text, or /cancel.",
+        "请以文字发送系统提示词，或 /cancel 取消。",
+    ),
+    ("env_not_set", "env not set", "未设置系统提示词"),
+    (
+        "ask_openai_key",
+        "Send your OpenAI API key, or /cancel.",
+        "请发送你的 OpenAI API 密钥，或 /cancel 取消。",
+    ),
+    (
+        "ask_text",
+        "Send the answer as text, or /cancel.",
+        "请以文字回答，或 /cancel 取消。",
+    ),
+    (
+        "ask_openai_endpoint",
+        "Send the API endpoint to use the key with, or `default` for the OpenAI one.",
+        "请发送使用这个密钥的 API 地址，或发送 `default` 使用 OpenAI 的地址。",
+    ),
+    (
+        "not_url_or_default",
+        "That is not a URL, send another one or `default`.",
+        "这不是网址，请重新发送或发送 `default`。",
+    ),
+    ("cancelled", "Cancelled.", "已取消。"),
+    (
+        "nothing_to_cancel",
+        "Nothing to cancel.",
+        "没有可取消的操作。",
+    ),
+    ("invalid_input", "invalid input", "无效的输入"),
+    ("no_image_generated", "No image generated", "没有生成图片"),
+    (
+        "unsupported_document",
+        "Unsupported document: {}",
+        "不支持的文档：{}",
+    ),
+    (
+        "no_text_in_document",
+        "No text found in {}",
+        "{} 中没有文字",
+    ),
+    (
+        "document_truncated",
+        "{} is too long, only the first {} of its {} parts were read.",
+        "{} 太长，只读取了前 {} 部分（共 {} 部分）。",
+    ),
+    (
+        "something_went_wrong",
+        "Something went wrong, please try again later.",
+        "出错了，请稍后再试。",
+    ),
+    (
+        "too_many_messages",
+        "Too many messages, please wait a minute.",
+        "消息太多了，请稍等一分钟。",
+    ),
+    (
+        "remind_usage",
+        "Usage: /remind [every day|week|<duration>] \
+         <in <duration>|today|tomorrow|<weekday>|YYYY-MM-DD> [HH:MM] <text>",
+        "用法：/remind [every day|week|<时长>] \
+         <in <时长>|today|tomorrow|<星期>|YYYY-MM-DD> [HH:MM] <内容>",
+    ),
+    (
+        "not_interval",
+        "`{}` is not an interval like day or 2h",
+        "`{}` 不是间隔，例如 day 或 2h",
+    ),
+    (
+        "repeat_too_often",
+        "Reminders can't repeat more than once a minute",
+        "提醒每分钟最多重复一次",
+    ),
+    (
+        "reminder_text_missing",
+        "What should I remind you of?",
+        "要提醒你什么？",
+    ),
+    (
+        "time_passed",
+        "That time has passed already",
+        "这个时间已经过去了",
+    ),
+    (
+        "time_not_in_timezone",
+        "That time doesn't exist in your timezone",
+        "你的时区中不存在这个时间",
+    ),
+    (
+        "reminder_set",
+        "I'll remind you at {} ({})",
+        "我会在 {}（{}）提醒你",
+    ),
+    ("reminder_repeat", " and every {} after", "，之后每 {} 一次"),
+    (
+        "reminder_cancel_hint",
+        ", /cancel_reminder {} to stop it.",
+        "，发送 /cancel_reminder {} 取消。",
+    ),
+    (
+        "no_reminders",
+        "No reminders in this chat.",
+        "这个聊天没有提醒。",
+    ),
+    ("reminders_title", "Reminders ({}):", "提醒（{}）："),
+    ("reminder_every", ", every {}", "，每 {}"),
+    ("reminder_cancelled", "Reminder cancelled.", "提醒已取消。"),
+    (
+        "no_such_reminder",
+        "No such reminder, see /reminders.",
+        "没有这个提醒，请查看 /reminders。",
+    ),
+    (
+        "settings_private",
+        "Open the settings with the menu button next to the message field.",
+        "请通过输入框旁的菜单按钮打开设置。",
+    ),
+    (
+        "settings_group",
+        "Open the settings of this chat from the menu button in your private chat with me.",
+        "请在与我的私聊中通过菜单按钮打开这个聊天的设置。",
+    ),
+    (
+        "settings_admins_only",
+        "Only administrators can change the settings of this chat.",
+        "只有管理员可以修改这个聊天的设置。",
+    ),
+    ("app_title", "Settings", "设置"),
+    ("app_chat", "Chat", "聊天"),
+    ("app_system_prompt", "System prompt", "系统提示词"),
+    ("app_model", "Model", "模型"),
+    (
+        "app_model_default",
+        "Default of the bot",
+        "机器人的默认模型",
+    ),
+    (
+        "app_endpoint",
+        "Endpoint (OpenAI compatible)",
+        "接口地址（兼容 OpenAI）",
+    ),
+    ("app_api_key", "API key", "API 密钥"),
+    ("app_keep_key", "Keep the current key", "保留当前的密钥"),
+    ("app_bot_key", "Key of the bot", "使用机器人的密钥"),
+    (
+        "app_remove_key",
+        "Remove the key of this chat",
+        "移除这个聊天的密钥",
+    ),
+    (
+        "app_history_length",
+        "Earlier messages sent to the model",
+        "发送给模型的历史消息数",
+    ),
+    ("app_save", "Save", "保存"),
+    (
+        "bot_admins_only",
+        "Only the administrators of the bot can change its profile.",
+        "只有机器人的管理员可以修改它的资料。",
+    ),
+    (
+        "unknown_timezone",
+        "Unknown timezone {}, try one like Asia/Shanghai",
+        "未知时区 {}，请使用类似 Asia/Shanghai 的时区",
+    ),
+    ("Credits", "Credits", "额度"),
+    (
+        "credit_packages",
+        "Credits pay for messages to the models, buy them with Telegram Stars:",
+        "额度用于向模型发送消息，可用 Telegram Stars 购买：",
+    ),
+    (
+        "credit_package",
+        "/buy {} - {} credits for {} ⭐",
+        "/buy {} - {} 额度，{} ⭐",
+    ),
+    ("invoice_title", "{} credits", "{} 额度"),
+    (
+        "invoice_description",
+        "{} messages to the models of the bot",
+        "向机器人的模型发送 {} 条消息",
+    ),
+    (
+        "invoice_outdated",
+        "This invoice is outdated, send /buy for a new one.",
+        "这张账单已过期，请发送 /buy 获取新的账单。",
+    ),
+    (
+        "credits_added",
+        "Thank you! {} credits added, you have {} now.",
+        "谢谢！已添加 {} 额度，你现在有 {} 额度。",
+    ),
+    ("credits_balance", "You have {} credits.", "你有 {} 额度。"),
+    (
+        "no_credits",
+        "You have no credits left, buy some with /buy.",
+        "你的额度已用完，请用 /buy 购买。",
+    ),
+    (
+        "document_credits",
+        "Reading this document takes about {} credits, buy more with /buy.",
+        "读取这个文档大约需要 {} 个额度，请用 /buy 购买更多。",
+    ),
+    (
+        "refunded",
+        "Refunded {} ⭐, {} credits were removed.",
+        "已退款 {} ⭐，扣除了 {} 额度。",
+    ),
+    (
+        "nothing_to_refund",
+        "Nothing to refund, only purchases whose credits are unused can be refunded.",
+        "没有可退款的购买，只有额度未使用的购买可以退款。",
+    ),
+    (
+        "groups_only",
+        "This command only works in groups.",
+        "这个命令只能在群组中使用。",
+    ),
+    (
+        "captcha_new_member",
+        "Welcome {}! Press the result of {} + {} within {} minutes to chat here.",
+        "欢迎 {}！请点击 {} + {} 的结果（{} 分钟内），之后就可以在这里发言。",
+    ),
+    (
+        "captcha_join_request",
+        "To join {}, press the result of {} + {} within {} minutes.",
+        "要加入 {}，请点击 {} + {} 的结果（{} 分钟内）。",
+    ),
+    ("captcha_solved", "Welcome!", "欢迎！"),
+    ("captcha_failed", "Wrong answer.", "答案错误。"),
+    (
+        "captcha_expired",
+        "This captcha has expired.",
+        "这个验证已过期。",
+    ),
+    (
+        "captcha_supergroups_only",
+        "Captchas only work in supergroups, members of basic groups can't be restricted.",
+        "入群验证只能在超级群组中使用，普通群组无法限制成员。",
+    ),
+    (
+        "captcha_not_yours",
+        "This captcha is for someone else.",
+        "这个验证是给别人的。",
+    ),
+    ("appeal", "Not a violation", "没有违规"),
+    (
+        "notice_warn",
+        "⚠️ {}, this message may break the rules of this group.",
+        "⚠️ {}，这条消息可能违反了本群规则。",
+    ),
+    (
+        "notice_delete",
+        "🗑 Removed a message of {} that breaks the rules of this group.",
+        "🗑 已删除 {} 违反本群规则的消息。",
+    ),
+    (
+        "notice_mute",
+        "🔇 Removed a message of {} that breaks the rules of this group and muted them for a day.",
+        "🔇 已删除 {} 违反本群规则的消息，并将其禁言一天。",
+    ),
+    (
+        "report",
+        "This message of {} may break the rules of your group.",
+        "{} 的这条消息可能违反了你的群组规则。",
+    ),
+    (
+        "appealed_notice",
+        "\nAppealed, an administrator can press the button to undo this.",
+        "\n已申诉，管理员可以点击按钮撤销。",
+    ),
+    (
+        "appeal_sent",
+        "The administrators can see your appeal.",
+        "管理员可以看到你的申诉。",
+    ),
+    (
+        "appeal_not_yours",
+        "Only the author or an administrator can appeal.",
+        "只有作者或管理员可以申诉。",
+    ),
+    (
+        "appeal_expired",
+        "This can't be appealed anymore.",
+        "已无法申诉。",
+    ),
+    ("appeal_accepted", "Undone.", "已撤销。"),
+    (
+        "appeal_accepted_notice",
+        "✅ An administrator found the message of {} fine.",
+        "✅ 管理员认为 {} 的消息没有问题。",
+    ),
+    ("restored_message", "{} wrote:\n{}", "{} 写道：\n{}"),
+    ("Title", "Title", "标题"),
+    ("state_on", "on", "开启"),
+    ("state_off", "off", "关闭"),
+    ("status_access", "Access: {}", "使用权限：{}"),
+    ("access_allowed", "allowed chat", "允许的聊天"),
+    (
+        "access_credits",
+        "paid with credits, {} left",
+        "使用额度付费，剩余 {}",
+    ),
+    ("status_language", "Language: {}", "语言：{}"),
+    ("status_trigger", "Answers: {}", "回答：{}"),
+    ("trigger_private", "every message", "每条消息"),
+    (
+        "trigger_group",
+        "commands, replies to the bot and mentions",
+        "命令、对机器人的回复和提及",
+    ),
+    ("status_voice_chat", "Voice chat: {}", "语音聊天：{}"),
+    ("status_model", "Model: {}", "模型：{}"),
+    ("status_provider", "Provider: {}", "服务商：{}"),
+    ("status_endpoint", "Endpoint: {}", "接口地址：{}"),
+    ("status_key", "API key: {} ({})", "API 密钥：{}（{}）"),
+    ("status_no_key", "API key: not set", "API 密钥：未设置"),
+    ("key_source_chat", "of this chat", "这个聊天的"),
+    ("key_source_global", "of the bot", "机器人的"),
+    (
+        "status_history",
+        "History: {} earlier messages",
+        "历史：{} 条之前的消息",
+    ),
+    (
+        "status_usage",
+        "Tokens used: about {} ({} prompt, {} completion)",
+        "已用 token：约 {}（提示 {}，回答 {}）",
+    ),
+    (
+        "status_rate_limit",
+        "Rate limit: {} of {} messages this minute",
+        "频率限制：本分钟 {} / {} 条消息",
+    ),
+    ("status_no_rate_limit", "Rate limit: none", "频率限制：无"),
+    ("status_captcha", "Captcha: {}", "入群验证：{}"),
+    ("status_moderation", "Moderation: {}", "内容审核：{}"),
+    ("status_title", "Title updates: {}", "标题更新：{}"),
+    ("status_template", "Template: {}", "模板：{}"),
+    ("status_delimiter", "Delimiter: \"{}\"", "分隔符：\"{}\""),
+    ("status_timezone", "Timezone: {}", "时区：{}"),
+    ("status_preview", "Title: {}", "标题：{}"),
+    ("title_preview", "The title will be: {}", "标题将会是：{}"),
+    (
+        "title_enable_hint",
+        "\nSend /enable to keep the title of this group up to date.",
+        "\n发送 /enable 让这个群组的标题保持更新。",
+    ),
+    (
+        "template_empty",
+        "The template has no segments, add some with /push.",
+        "模板中没有片段，请用 /push 添加。",
+    ),
+];
+
+/// Text of `key` in `lang`, the key itself when the catalog has no such entry.
+pub fn text(lang: Lang, key: &str) -> &str {
+    match CATALOG.iter().find(|(k, _, _)| *k == key) {
+        Some((_, en, zh)) => match lang {
+            Lang::En => en,
+            Lang::Zh => zh,
+        },
+        None => key,
+    }
+}
+
+/// Text of `key` in `lang` with its placeholders filled with `args`.
+pub fn format(lang: Lang, key: &str, args: &[&str]) -> String {
+    let mut parts = text(lang, key).split("{}");
+    let mut text = parts.next().unwrap_or_default().to_string();
+    for (index, part) in parts.enumerate() {
+        text += args.get(index).copied().unwrap_or_default();
+        text += part;
+    }
+    text
+}
+
+fn language_key(m: &Message) -> String {
+    format!("INDEX_CHAT_LANGUAGE:{}", m.chat.id.0)
+}
+
+/// Language of the chat set with `/set_language`, or else the one of the sender.
+pub async fn language(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<Lang, WorkerError> {
+    let chat = bot.store(env)?.get(&language_key(m)).text().await?;
+    let user = m.from.as_ref().and_then(|user| user.language_code.clone());
+    Ok([chat, user, bot.var(env, VAR_LANGUAGE)]
+        .into_iter()
+        .flatten()
+        .find_map(|code| Lang::from_code(&code))
+        .unwrap_or_default())
+}
+
+/// Set the language of the chat, `None` to follow the language of each user again.
+pub async fn set_language(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    lang: Option<Lang>,
+) -> Result<(), WorkerError> {
+    let store = bot.store(env)?;
+    match lang {
+        Some(lang) => store.put(&language_key(m), lang.code())?.execute().await?,
+        None => store.delete(&language_key(m)).await?,
+    }
+    Ok(())
+}
+
+#[test]
+fn test_catalog() {
+    for (key, en, zh) in CATALOG {
+        assert_eq!(
+            en.matches("{}").count(),
+            zh.matches("{}").count(),
+            "{}",
+            key
+        );
+    }
+    assert_eq!(Lang::from_code("zh-hans"), Some(Lang::Zh));
+    assert_eq!(Lang::from_code("de"), None);
+    assert_eq!(
+        format(
+            Lang::Zh,
+            "reminder_set",
+            &["2024-01-01 09:00", "Asia/Shanghai"]
+        ),
+        "我会在 2024-01-01 09:00（Asia/Shanghai）提醒你"
+    );
+    assert_eq!(text(Lang::Zh, "Misc"), "Misc");
+}
@@ -0,0 +1,89 @@
+//! Bot commands found through the `bot_command` entities of a message.
+use telegram_types::bot::types::{Message, MessageEntity, MessageEntityKind};
+
+/// A command at the start of a message text or caption, e.g. `/chat@fdkevin_bot hello`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invocation {
+    /// Lower-cased command name, without the slash.
+    pub command: String,
+    /// Bot the command is addressed to, when written as `/command@username`.
+    pub username: Option<String>,
+    /// Text after the command, line breaks kept.
+    pub args: String,
+}
+
+impl Invocation {
+    pub fn parse(m: &Message) -> Option<Self> {
+        match (&m.text, &m.caption) {
+            (Some(text), _) => Self::parse_text(text, &m.entities),
+            (None, Some(caption)) => Self::parse_text(caption, &m.caption_entities),
+            (None, None) => None,
+        }
+    }
+
+    fn parse_text(text: &str, entities: &[MessageEntity]) -> Option<Self> {
+        let entity = entities
+            .iter()
+            .find(|entity| entity.kind == MessageEntityKind::BotCommand && entity.offset == 0)?;
+        let end = byte_offset(text, entity.length as usize)?;
+        let name = text[..end].trim_start_matches('/');
+        let (command, username) = match name.split_once('@') {
+            Some((command, username)) => (command, Some(username.to_string())),
+            None => (name, None),
+        };
+        Some(Self {
+            command: command.to_ascii_lowercase(),
+            username,
+            args: text[end..].trim().to_string(),
+        })
+    }
+
+    /// Whether the command is meant for the bot with the given username.
+    pub fn is_for(&self, username: Option<&str>) -> bool {
+        match (&self.username, username) {
+            (None, _) => true,
+            (Some(target), Some(username)) => target.eq_ignore_ascii_case(username),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+// entity offsets count UTF-16 code units
+fn byte_offset(text: &str, utf16_offset: usize) -> Option<usize> {
+    let mut units = 0;
+    for (index, c) in text.char_indices() {
+        if units == utf16_offset {
+            return Some(index);
+        }
+        units += c.len_utf16();
+    }
+    (units == utf16_offset).then_some(text.len())
+}
+
+/// Text or caption of a message without the leading command.
+pub fn message_args(m: &Message) -> Option<String> {
+    match Invocation::parse(m) {
+        Some(invocation) => Some(invocation.args).filter(|args| !args.is_empty()),
+        None => m.text.clone().or_else(|| m.caption.clone()),
+    }
+}
+
+#[test]
+fn test_invocation() {
+    let entity = |length| MessageEntity {
+        kind: MessageEntityKind::BotCommand,
+        offset: 0,
+        length,
+        url: None,
+        user: None,
+    };
+    let invocation = Invocation::parse_text("/Chat@FDKevin_Bot\n你好 👋", &[entity(17)]).unwrap();
+    assert_eq!(invocation.command, "chat");
+    assert_eq!(invocation.args, "你好 👋");
+    assert!(invocation.is_for(Some("fdkevin_bot")));
+    assert!(!invocation.is_for(Some("other_bot")));
+    let invocation = Invocation::parse_text("/start", &[entity(6)]).unwrap();
+    assert_eq!(invocation.command, "start");
+    assert_eq!(invocation.username, None);
+    assert!(Invocation::parse_text("hello /start", &[]).is_none());
+}
@@ -1,18 +1,49 @@
+pub mod args;
 pub mod bot;
+pub mod captcha;
 pub mod chat;
 pub mod command;
+pub mod credits;
+pub mod dialog;
+pub mod document;
+pub mod feedback;
+pub mod i18n;
+pub mod invocation;
+pub mod media;
+pub mod methods;
+pub mod middleware;
+pub mod moderation;
+pub mod multipart;
 pub mod openai;
+pub mod platform;
+pub mod profile;
+pub mod reminder;
+pub mod reply;
+pub mod store;
+pub mod title;
+pub mod webapp;
 
 use cfg_if::cfg_if;
 use sha2::{Digest, Sha256};
 use worker::{
-    console_error, console_log, event, Date, Env, Error as WorkerError, Request, Response, Router,
+    event, Date, Error as WorkerError, Request, Response, Router, ScheduleContext, ScheduledEvent,
 };
 
-use bot::Bot;
+use bot::{Bot, CommandInfo};
+use platform::Env;
 
 pub const TELEGRAM_API_TOKEN: &str = "TELEGRAM_API_TOKEN";
-const VAR_KV_STORE: &str = "KV_STORE";
+/// Var naming the KV namespace binding of the bots.
+pub const VAR_KV_STORE: &str = "KV_STORE";
+const CATEGORY_CHAT: &str = "Chat";
+const CATEGORY_SETTINGS: &str = "Settings";
+const CATEGORY_REMINDERS: &str = "Reminders";
+const CATEGORY_CREDITS: &str = "Credits";
+const CATEGORY_TITLE: &str = "Title";
+/// Names of the bots served by the worker, comma separated. Unset means a single unnamed bot.
+const VAR_BOTS: &str = "BOTS";
+/// Commands enabled for a bot, comma separated. Unset means all of them.
+const VAR_COMMANDS: &str = "COMMANDS";
 
 // cloudflare workers log api workarround
 cfg_if! {
@@ -38,47 +69,53 @@ fn log_request(req: &Request) {
 
 pub async fn main_inner(
     req: Request,
-    env: Env,
+    env: worker::Env,
     _ctx: worker::Context,
 ) -> Result<Response, WorkerError> {
     log_request(&req);
     set_panic_hook();
 
-    // Bot
-    let mut bot = Bot::new_with_env(&env, TELEGRAM_API_TOKEN, VAR_KV_STORE)?;
-
-    // Commands
-    bot.register_command("echo", command::echo);
-    bot.register_command("start", command::start);
-    bot.register_command("chat_info", command::chat_info);
-    bot.register_command("help", command::help);
-    bot.register_command("fetch", command::fetch);
-    bot.register_command("chat", command::call_chat_api);
-    bot.register_command("sync_commands", command::sync_commands);
-    bot.register_command("set_chat_env", command::set_chat_env);
-    bot.register_command("get_chat_env", command::get_chat_env);
-    bot.register_command("clear", command::clear_chat_context);
-    bot.register_command("set_openai_key", command::set_user_openai_key);
-    bot.register_command("set_openai_endpoint", command::set_user_openai_endpoint);
-
-    bot.with_default(command::call_chat_api);
-
-    let tg_bot_token_sha256 = sha256(env.secret(TELEGRAM_API_TOKEN.as_ref())?.to_string());
+    // Bot, picked by the token hash the request path starts with
+    let route = req
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let Some(bot) = bots(&Env::from(&env))?
+        .into_iter()
+        .find(|bot| sha256(bot.token.clone()) == route)
+    else {
+        return Response::error("Not Found", 404);
+    };
+    console_log!("Serving bot {:?}", bot.name);
 
     // Router
     let router = Router::with_data(bot)
+        .get_async(format!("/{}/", route).as_str(), |req, ctx| async move {
+            let bot = ctx.data;
+            let target = format!("{}updates", req.url()?);
+            console_log!("Setting up webhook, URL: {}", target);
+            bot.setup_webhook(target).await?;
+            let app_url = format!("{}app", req.url()?);
+            if let Err(e) = webapp::setup(&bot, &Env::from(&ctx.env), app_url).await {
+                console_error!("Failed to set up the settings app: {}", e);
+            }
+            Response::from_json(&bot.get_me().await?)
+        })
+        .get_async(format!("/{}/app", route).as_str(), webapp::page)
+        .get_async(format!("/{}/app/chats", route).as_str(), webapp::chats)
         .get_async(
-            format!("/{}/", tg_bot_token_sha256).as_str(),
-            |req, ctx| async move {
-                let bot = ctx.data;
-                let target = format!("{}updates", req.url()?);
-                console_log!("Setting up webhook, URL: {}", target);
-                bot.setup_webhook(target).await?;
-                Response::from_json(&bot.get_me().await?)
-            },
+            format!("/{}/app/settings/:chat_id", route).as_str(),
+            webapp::get_settings,
+        )
+        .post_async(
+            format!("/{}/app/settings/:chat_id", route).as_str(),
+            webapp::put_settings,
         )
         .post_async(
-            format!("/{}/updates", tg_bot_token_sha256).as_str(),
+            format!("/{}/updates", route).as_str(),
             |mut req, ctx| async move { Bot::process_update(&mut req, ctx).await },
         );
 
@@ -86,14 +123,351 @@ pub async fn main_inner(
     router.run(req, env).await
 }
 
-fn sha256(token: String) -> String {
+/// The bots served by the deployment, see `VAR_BOTS`. A named bot that fails to set up, e.g.
+/// without its token, is logged and left out so the others keep working.
+pub fn bots<'a>(env: &Env) -> Result<Vec<Bot<'a>>, WorkerError> {
+    let names = env
+        .var(VAR_BOTS)
+        .map(|var| var.to_string())
+        .unwrap_or_default();
+    let names = names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<&str>>();
+    match names.is_empty() {
+        true => Ok(vec![new_bot(env, "")?]),
+        false => Ok(names
+            .into_iter()
+            .filter_map(|name| match new_bot(env, name) {
+                Ok(bot) => Some(bot),
+                Err(e) => {
+                    console_error!("Failed to set up bot {:?}: {}", name, e);
+                    None
+                }
+            })
+            .collect()),
+    }
+}
+
+fn new_bot<'a>(env: &Env, name: &str) -> Result<Bot<'a>, WorkerError> {
+    let mut bot = Bot::new_with_env(env, name, TELEGRAM_API_TOKEN, VAR_KV_STORE)?;
+
+    // Commands
+    bot.register_command(
+        "echo",
+        command::echo,
+        CommandInfo::new("Let the bot say something")
+            .translation("zh", "让机器人说句话")
+            .args(command::echo_args())
+            .example("/echo hello"),
+    );
+    bot.register_command(
+        "start",
+        command::start,
+        CommandInfo::new("Show the bot version").translation("zh", "显示机器人版本"),
+    );
+    bot.register_command(
+        "chat_info",
+        command::chat_info,
+        CommandInfo::new("Show details of this chat")
+            .translation("zh", "显示这个聊天的详情")
+            .hidden(),
+    );
+    bot.register_command(
+        "help",
+        command::help,
+        CommandInfo::new("List commands or show how to use one")
+            .translation("zh", "列出命令或显示命令用法")
+            .args(command::help_args())
+            .example("/help image"),
+    );
+    bot.register_command(
+        "fetch",
+        command::fetch,
+        CommandInfo::new("Fetch a URL and show the response")
+            .translation("zh", "获取网址并显示响应")
+            .args(command::fetch_args())
+            .example("/fetch https://example.com")
+            .hidden(),
+    );
+    bot.register_command(
+        "chat",
+        command::call_chat_api,
+        CommandInfo::new("Talk to the chat model")
+            .translation("zh", "与聊天模型对话")
+            .category(CATEGORY_CHAT)
+            .alias("c")
+            .example("/chat What is the capital of France?"),
+    );
+    bot.register_command(
+        "image",
+        command::image,
+        CommandInfo::new("Generate images from a prompt")
+            .translation("zh", "根据提示词生成图片")
+            .category(CATEGORY_CHAT)
+            .alias("img")
+            .args(command::image_args())
+            .example("/image a red fox in the snow")
+            .example("/image --size 1792x1024 --quality hd --n 2 a lighthouse at dusk"),
+    );
+    bot.register_command(
+        "clear",
+        command::clear_chat_context,
+        CommandInfo::new("Forget the conversation and the shared document")
+            .translation("zh", "忘记对话和共享的文档")
+            .category(CATEGORY_CHAT)
+            .alias("reset"),
+    );
+    bot.register_command(
+        "set_chat_env",
+        command::set_chat_env,
+        CommandInfo::new("Set the system prompt of this chat")
+            .translation("zh", "设置这个聊天的系统提示词")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_chat_env_args())
+            .example("/set_chat_env You are a helpful assistant."),
+    );
+    bot.register_command(
+        "get_chat_env",
+        command::get_chat_env,
+        CommandInfo::new("Show the system prompt of this chat")
+            .translation("zh", "显示这个聊天的系统提示词")
+            .category(CATEGORY_SETTINGS),
+    );
+    bot.register_command(
+        "set_openai_key",
+        command::set_user_openai_key,
+        CommandInfo::new("Use your own OpenAI API key in this chat")
+            .translation("zh", "在这个聊天中使用你自己的 OpenAI API 密钥")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_user_openai_key_args()),
+    );
+    bot.register_command(
+        "set_openai_endpoint",
+        command::set_user_openai_endpoint,
+        CommandInfo::new("Use another OpenAI compatible endpoint in this chat")
+            .translation("zh", "在这个聊天中使用其他兼容 OpenAI 的地址")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_user_openai_endpoint_args())
+            .example("/set_openai_endpoint https://api.openai.com/v1/chat/completions"),
+    );
+    bot.register_command(
+        "set_voice_chat",
+        command::set_voice_chat,
+        CommandInfo::new("Answer voice messages instead of only transcribing them")
+            .translation("zh", "回答语音消息，而不只是转写")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_voice_chat_args()),
+    );
+    bot.register_command(
+        "remind",
+        command::remind,
+        CommandInfo::new("Remind you of something later")
+            .translation("zh", "稍后提醒你某件事")
+            .category(CATEGORY_REMINDERS)
+            .args(command::remind_args())
+            .example("/remind in 2h check deploy")
+            .example("/remind tomorrow 9:00 standup")
+            .example("/remind every week friday 17:00 write the weekly report"),
+    );
+    bot.register_command(
+        "reminders",
+        command::reminders,
+        CommandInfo::new("List the reminders of this chat")
+            .translation("zh", "列出这个聊天的提醒")
+            .category(CATEGORY_REMINDERS),
+    );
+    bot.register_command(
+        "cancel_reminder",
+        command::cancel_reminder,
+        CommandInfo::new("Cancel a reminder")
+            .translation("zh", "取消提醒")
+            .category(CATEGORY_REMINDERS)
+            .args(command::cancel_reminder_args()),
+    );
+    bot.register_command(
+        "set_timezone",
+        command::set_timezone,
+        CommandInfo::new("Set the timezone of this chat")
+            .translation("zh", "设置这个聊天的时区")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_timezone_args())
+            .example("/set_timezone Asia/Shanghai"),
+    );
+    bot.register_command(
+        "settings",
+        command::settings,
+        CommandInfo::new("Change the settings of this chat in an app")
+            .translation("zh", "在应用中修改这个聊天的设置")
+            .category(CATEGORY_SETTINGS),
+    );
+    bot.register_command(
+        "set_language",
+        command::set_language,
+        CommandInfo::new("Set the language of the replies in this chat")
+            .translation("zh", "设置这个聊天中回复的语言")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_language_args())
+            .example("/set_language zh"),
+    );
+    bot.register_command(
+        "status",
+        command::status,
+        CommandInfo::new("Show the settings of this chat").translation("zh", "显示这个聊天的设置"),
+    );
+    bot.register_command(
+        "enable",
+        command::enable,
+        CommandInfo::new("Keep the title of this group up to date")
+            .translation("zh", "让这个群组的标题保持更新")
+            .category(CATEGORY_TITLE),
+    );
+    bot.register_command(
+        "disable",
+        command::disable,
+        CommandInfo::new("Stop updating the title of this group")
+            .translation("zh", "停止更新这个群组的标题")
+            .category(CATEGORY_TITLE),
+    );
+    bot.register_command(
+        "set_template",
+        command::set_template,
+        CommandInfo::new("Set the title template, split at the delimiter")
+            .translation("zh", "设置标题模板，按分隔符拆分为片段")
+            .category(CATEGORY_TITLE)
+            .args(command::set_template_args())
+            .example("/set_template Team {date}"),
+    );
+    bot.register_command(
+        "set_delimiter",
+        command::set_delimiter,
+        CommandInfo::new("Set the delimiter between segments of the title template")
+            .translation("zh", "设置标题模板片段之间的分隔符")
+            .category(CATEGORY_TITLE)
+            .args(command::set_delimiter_args())
+            .example("/set_delimiter \" | \""),
+    );
+    bot.register_command(
+        "push",
+        command::push,
+        CommandInfo::new("Add a segment to the end of the title template")
+            .translation("zh", "在标题模板末尾添加片段")
+            .category(CATEGORY_TITLE)
+            .args(command::push_args())
+            .example("/push {weekday}"),
+    );
+    bot.register_command(
+        "push_front",
+        command::push_front,
+        CommandInfo::new("Add a segment to the start of the title template")
+            .translation("zh", "在标题模板开头添加片段")
+            .category(CATEGORY_TITLE)
+            .args(command::push_front_args()),
+    );
+    bot.register_command(
+        "pop",
+        command::pop,
+        CommandInfo::new("Remove the last segment of the title template")
+            .translation("zh", "移除标题模板的最后一个片段")
+            .category(CATEGORY_TITLE),
+    );
+    bot.register_command(
+        "pop_front",
+        command::pop_front,
+        CommandInfo::new("Remove the first segment of the title template")
+            .translation("zh", "移除标题模板的第一个片段")
+            .category(CATEGORY_TITLE),
+    );
+    bot.register_command(
+        "set_captcha",
+        command::set_captcha,
+        CommandInfo::new("Ask new members of this group to solve a captcha")
+            .translation("zh", "要求这个群组的新成员完成验证")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_captcha_args())
+            .example("/set_captcha on"),
+    );
+    bot.register_command(
+        "set_moderation",
+        command::set_moderation,
+        CommandInfo::new("Check messages of new members of this group against a policy")
+            .translation("zh", "按规则检查这个群组新成员的消息")
+            .category(CATEGORY_SETTINGS)
+            .args(command::set_moderation_args())
+            .example("/set_moderation delete No advertising or crypto offers.")
+            .example("/set_moderation off"),
+    );
+    bot.register_command(
+        "buy",
+        command::buy,
+        CommandInfo::new("Buy credits with Telegram Stars")
+            .translation("zh", "用 Telegram Stars 购买额度")
+            .category(CATEGORY_CREDITS)
+            .args(command::buy_args())
+            .example("/buy 100"),
+    );
+    bot.register_command(
+        "credits",
+        command::credits,
+        CommandInfo::new("Show your credits")
+            .translation("zh", "查看你的额度")
+            .category(CATEGORY_CREDITS),
+    );
+    bot.register_command(
+        "refund",
+        command::refund,
+        CommandInfo::new("Refund your last purchase of credits")
+            .translation("zh", "退还你最近一次购买的额度")
+            .category(CATEGORY_CREDITS),
+    );
+    bot.register_command(
+        "cancel",
+        command::cancel,
+        CommandInfo::new("Cancel the current question of the bot")
+            .translation("zh", "取消机器人当前的提问"),
+    );
+    bot.register_command(
+        "sync_commands",
+        command::sync_commands,
+        CommandInfo::new("Update the command menu and profile of the bot")
+            .translation("zh", "更新机器人的命令菜单和资料")
+            .hidden(),
+    );
+    if let Some(enabled) = bot.var(env, VAR_COMMANDS) {
+        let enabled = enabled.split(',').map(str::trim).collect::<Vec<&str>>();
+        bot.commands
+            .retain(|command, _| enabled.contains(&command.as_str()));
+    }
+
+    bot.with_default(command::default_reply);
+
+    // Dialogs
+    bot.register_dialog("set_chat_env", command::set_chat_env_dialog);
+    bot.register_dialog("set_openai_key", command::set_user_openai_key_dialog);
+
+    // Middlewares
+    bot.with_middleware(middleware::locale);
+    bot.with_middleware(middleware::errors);
+    bot.with_middleware(middleware::log);
+    bot.with_middleware(middleware::auth);
+    bot.with_middleware(middleware::moderate);
+    bot.with_middleware(middleware::rate_limit);
+    Ok(bot)
+}
+
+pub fn sha256(token: String) -> String {
     let mut hasher = Sha256::new();
     hasher.update(token);
     format!("{:x}", hasher.finalize())
 }
 
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Response, WorkerError> {
+pub async fn main(
+    req: Request,
+    env: worker::Env,
+    ctx: worker::Context,
+) -> Result<Response, WorkerError> {
     match main_inner(req, env, ctx).await {
         Ok(res) => Ok(res),
         Err(e) => {
@@ -104,12 +478,42 @@ pub async fn main(req: Request, env: Env, ctx: worker::Context) -> Result<Respon
     }
 }
 
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: worker::Env, _ctx: ScheduleContext) {
+    set_panic_hook();
+    run_scheduled(&Env::from(&env)).await;
+}
+
+/// The work of the cron trigger, for every bot.
+pub async fn run_scheduled(env: &Env) {
+    let bots = match bots(env) {
+        Ok(bots) => bots,
+        Err(e) => return console_error!("Error occurred: {}", e),
+    };
+    for bot in bots {
+        if let Err(e) = reminder::deliver_due(env, &bot).await {
+            console_error!("Failed to deliver reminders of bot {:?}: {}", bot.name, e);
+        }
+        if let Err(e) = captcha::expire_due(env, &bot).await {
+            console_error!("Failed to expire captchas of bot {:?}: {}", bot.name, e);
+        }
+        if let Err(e) = title::update_titles(env, &bot).await {
+            console_error!("Failed to update titles of bot {:?}: {}", bot.name, e);
+        }
+    }
+}
+
 #[test]
 fn test_sha256() {
     let token = "476884080:AAH-qyccfEpbCh8Pr1bw-wXL67EWGTW337I";
     println!("{}", sha256(token.to_string()))
 }
 
-pub fn bot_store(_env: &Env) -> Result<worker::kv::KvStore, WorkerError> {
-    _env.kv("FDKEVIN_BOT_STORE")
+#[test]
+fn test_new_bot() {
+    let vars = [(TELEGRAM_API_TOKEN, "1:token"), (VAR_KV_STORE, "KV_STORE")]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    // registering an alias that is taken panics
+    let bot = new_bot(&Env::local(vars.into(), None), "").unwrap();
+    assert_eq!(bot.resolve("c").map(|(name, _)| name), Some("chat"));
 }
@@ -0,0 +1,95 @@
+use telegram_types::bot::types::{FileId, Message};
+
+/// Mime type Telegram uses for compressed photos.
+const PHOTO_MIME_TYPE: &str = "image/jpeg";
+
+/// A downloadable file attached to a message.
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub file_id: FileId,
+    pub mime_type: String,
+    /// File name to use when uploading the content elsewhere.
+    pub file_name: String,
+}
+
+impl Attachment {
+    fn new(file_id: &FileId, mime_type: &str, name: &str) -> Self {
+        Self {
+            file_id: file_id.clone(),
+            mime_type: mime_type.to_string(),
+            file_name: format!("{}.{}", name, extension(mime_type)),
+        }
+    }
+}
+
+/// The image attached to a message, either a photo or an image document.
+///
+/// For photos the largest available size is picked.
+pub fn image(m: &Message) -> Option<Attachment> {
+    if let Some(photo) = m.photo.iter().max_by_key(|p| p.width * p.height) {
+        return Some(Attachment::new(&photo.file_id, PHOTO_MIME_TYPE, "photo"));
+    }
+    let document = m.document.as_ref()?;
+    match &document.mime_type {
+        Some(mime_type) if mime_type.starts_with("image/") => {
+            Some(Attachment::new(&document.file_id, mime_type, "image"))
+        }
+        _ => None,
+    }
+}
+
+/// The spoken content of a message: a voice note, an audio file or a video note.
+pub fn voice(m: &Message) -> Option<Attachment> {
+    if let Some(voice) = &m.voice {
+        let mime_type = voice.mime_type.as_deref().unwrap_or("audio/ogg");
+        return Some(Attachment::new(&voice.file_id, mime_type, "voice"));
+    }
+    if let Some(audio) = &m.audio {
+        let mime_type = audio.mime_type.as_deref().unwrap_or("audio/mpeg");
+        return Some(Attachment::new(&audio.file_id, mime_type, "audio"));
+    }
+    if let Some(video_note) = &m.video_note {
+        return Some(Attachment::new(
+            &video_note.file_id,
+            "video/mp4",
+            "video_note",
+        ));
+    }
+    None
+}
+
+/// A non-image document attached to a message.
+pub fn document(m: &Message) -> Option<Attachment> {
+    let document = m.document.as_ref()?;
+    let mime_type = document
+        .mime_type
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    if mime_type.starts_with("image/") {
+        return None;
+    }
+    let mut attachment = Attachment::new(&document.file_id, mime_type, "document");
+    if let Some(file_name) = &document.file_name {
+        attachment.file_name = file_name.clone();
+    }
+    Some(attachment)
+}
+
+/// Whether the message carries anything the chat model can take as input.
+pub fn has_attachment(m: &Message) -> bool {
+    image(m).is_some() || voice(m).is_some() || document(m).is_some()
+}
+
+fn extension(mime_type: &str) -> &str {
+    match mime_type {
+        "image/jpeg" => "jpg",
+        "audio/mpeg" => "mp3",
+        "audio/mp4" | "audio/x-m4a" | "audio/m4a" => "m4a",
+        "audio/x-wav" | "audio/wav" => "wav",
+        "audio/ogg" | "audio/opus" => "ogg",
+        _ => mime_type
+            .split_once('/')
+            .map(|(_, subtype)| subtype)
+            .unwrap_or("bin"),
+    }
+}
@@ -0,0 +1,429 @@
+//! Bot API methods not covered by `telegram_types`.
+use serde::{Deserialize, Serialize};
+use telegram_types::bot::methods::{ChatTarget, Method};
+use telegram_types::bot::types::{File, FileId, FileToSend, Message, MessageId};
+
+/// Use this method to get basic info about a file and prepare it for downloading.
+#[derive(Serialize, Debug, Clone)]
+pub struct GetFile {
+    pub file_id: FileId,
+}
+
+impl Method for GetFile {
+    const NAME: &'static str = "getFile";
+    type Item = File;
+}
+
+/// A photo to be sent as part of a media group.
+#[derive(Serialize, Debug, Clone)]
+pub struct InputMediaPhoto {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub media: FileToSend,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+}
+
+impl InputMediaPhoto {
+    pub fn new(media: FileToSend) -> Self {
+        Self {
+            kind: "photo",
+            media,
+            caption: None,
+        }
+    }
+}
+
+/// Use this method to send a group of photos as an album.
+#[derive(Serialize, Debug, Clone)]
+pub struct SendMediaGroup<'a> {
+    pub chat_id: ChatTarget<'a>,
+    pub media: Vec<InputMediaPhoto>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to_message_id: Option<MessageId>,
+}
+
+impl<'a> Method for SendMediaGroup<'a> {
+    const NAME: &'static str = "sendMediaGroup";
+    type Item = Vec<Message>;
+}
+
+/// Reaction type, only plain emoji reactions are supported.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReactionType {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub emoji: String,
+}
+
+impl ReactionType {
+    pub fn emoji<S: AsRef<str>>(emoji: S) -> Self {
+        Self {
+            kind: "emoji",
+            emoji: emoji.as_ref().to_string(),
+        }
+    }
+}
+
+/// Use this method to change the chosen reactions on a message.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetMessageReaction<'a> {
+    pub chat_id: ChatTarget<'a>,
+    pub message_id: MessageId,
+    pub reaction: Vec<ReactionType>,
+}
+
+impl<'a> Method for SetMessageReaction<'a> {
+    const NAME: &'static str = "setMessageReaction";
+    type Item = bool;
+}
+
+/// Use this method to specify a URL to receive updates through, `telegram_types` only knows
+/// some of the update types.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetWebhook {
+    pub url: String,
+    pub allowed_updates: Vec<&'static str>,
+}
+
+impl Method for SetWebhook {
+    const NAME: &'static str = "setWebhook";
+    type Item = bool;
+}
+
+/// A bot command shown in the Telegram command menu.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BotCommand {
+    pub command: String,
+    pub description: String,
+}
+
+/// The chats a list of bot commands applies to.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BotCommandScope {
+    #[default]
+    Default,
+    AllPrivateChats,
+    AllGroupChats,
+    AllChatAdministrators,
+}
+
+impl BotCommandScope {
+    pub const ALL: [BotCommandScope; 4] = [
+        BotCommandScope::Default,
+        BotCommandScope::AllPrivateChats,
+        BotCommandScope::AllGroupChats,
+        BotCommandScope::AllChatAdministrators,
+    ];
+
+    /// Whether a command registered for `scope` belongs to the list of this scope.
+    ///
+    /// Telegram only shows the list of the most specific scope, so broader commands are repeated.
+    pub fn includes(self, scope: BotCommandScope) -> bool {
+        use BotCommandScope::*;
+        matches!(
+            (self, scope),
+            (_, Default)
+                | (AllPrivateChats, AllPrivateChats)
+                | (AllGroupChats | AllChatAdministrators, AllGroupChats)
+                | (AllChatAdministrators, AllChatAdministrators)
+        )
+    }
+}
+
+/// Use this method to change the list of the bot's commands.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetMyCommands {
+    pub commands: Vec<BotCommand>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<BotCommandScope>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_code: Option<String>,
+}
+
+impl Method for SetMyCommands {
+    const NAME: &'static str = "setMyCommands";
+    type Item = bool;
+}
+
+/// Use this method to change the bot's name.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetMyName {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_code: Option<String>,
+}
+
+impl Method for SetMyName {
+    const NAME: &'static str = "setMyName";
+    type Item = bool;
+}
+
+/// Use this method to change the bot's description, shown in empty chats with the bot.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetMyDescription {
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_code: Option<String>,
+}
+
+impl Method for SetMyDescription {
+    const NAME: &'static str = "setMyDescription";
+    type Item = bool;
+}
+
+/// Use this method to change the bot's short description, shown on its profile page.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetMyShortDescription {
+    pub short_description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language_code: Option<String>,
+}
+
+impl Method for SetMyShortDescription {
+    const NAME: &'static str = "setMyShortDescription";
+    type Item = bool;
+}
+
+/// The button next to the message input field.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MenuButton {
+    Commands,
+    /// Opens a Mini App.
+    WebApp {
+        text: String,
+        web_app: WebAppInfo,
+    },
+    Default,
+}
+
+/// A Mini App, `url` has to be HTTPS.
+#[derive(Serialize, Debug, Clone)]
+pub struct WebAppInfo {
+    pub url: String,
+}
+
+/// Use this method to change the bot's menu button, the default one when `chat_id` is unset.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetChatMenuButton {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<ChatTarget<'static>>,
+    pub menu_button: MenuButton,
+}
+
+impl Method for SetChatMenuButton {
+    const NAME: &'static str = "setChatMenuButton";
+    type Item = bool;
+}
+
+/// A portion of the price of an invoice, in the smallest units of the currency.
+#[derive(Serialize, Debug, Clone)]
+pub struct LabeledPrice {
+    pub label: String,
+    pub amount: u64,
+}
+
+/// Use this method to send an invoice, payments in Telegram Stars (`XTR`) need no provider.
+#[derive(Serialize, Debug, Clone)]
+pub struct SendInvoice<'a> {
+    pub chat_id: ChatTarget<'a>,
+    pub title: String,
+    pub description: String,
+    pub payload: String,
+    pub currency: String,
+    pub prices: Vec<LabeledPrice>,
+}
+
+impl<'a> Method for SendInvoice<'a> {
+    const NAME: &'static str = "sendInvoice";
+    type Item = Message;
+}
+
+/// Use this method to confirm or refuse a payment before it is made.
+#[derive(Serialize, Debug, Clone)]
+pub struct AnswerPreCheckoutQuery {
+    pub pre_checkout_query_id: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_message: Option<String>,
+}
+
+impl Method for AnswerPreCheckoutQuery {
+    const NAME: &'static str = "answerPreCheckoutQuery";
+    type Item = bool;
+}
+
+/// Use this method to refund a payment in Telegram Stars.
+#[derive(Serialize, Debug, Clone)]
+pub struct RefundStarPayment {
+    pub user_id: i64,
+    pub telegram_payment_charge_id: String,
+}
+
+impl Method for RefundStarPayment {
+    const NAME: &'static str = "refundStarPayment";
+    type Item = bool;
+}
+
+/// Use this method to answer a press of an inline keyboard button.
+#[derive(Serialize, Debug, Clone)]
+pub struct AnswerCallbackQuery {
+    pub callback_query_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Shown as an alert instead of a notification at the top of the chat.
+    pub show_alert: bool,
+}
+
+impl AnswerCallbackQuery {
+    pub fn new<S: AsRef<str>>(callback_query_id: S, text: Option<String>) -> Self {
+        Self {
+            callback_query_id: callback_query_id.as_ref().to_string(),
+            text,
+            show_alert: false,
+        }
+    }
+}
+
+impl Method for AnswerCallbackQuery {
+    const NAME: &'static str = "answerCallbackQuery";
+    type Item = bool;
+}
+
+/// What a member of a group is allowed to send, permissions Telegram leaves out are denied.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct ChatPermissions {
+    pub can_send_messages: bool,
+    pub can_send_audios: bool,
+    pub can_send_documents: bool,
+    pub can_send_photos: bool,
+    pub can_send_videos: bool,
+    pub can_send_video_notes: bool,
+    pub can_send_voice_notes: bool,
+    pub can_send_polls: bool,
+    pub can_send_other_messages: bool,
+    pub can_add_web_page_previews: bool,
+    pub can_change_info: bool,
+    pub can_invite_users: bool,
+    pub can_pin_messages: bool,
+    pub can_manage_topics: bool,
+}
+
+impl ChatPermissions {
+    /// All permissions or none, all of them lifts the restrictions of a member.
+    pub fn all(allowed: bool) -> Self {
+        Self {
+            can_send_messages: allowed,
+            can_send_audios: allowed,
+            can_send_documents: allowed,
+            can_send_photos: allowed,
+            can_send_videos: allowed,
+            can_send_video_notes: allowed,
+            can_send_voice_notes: allowed,
+            can_send_polls: allowed,
+            can_send_other_messages: allowed,
+            can_add_web_page_previews: allowed,
+            can_change_info: allowed,
+            can_invite_users: allowed,
+            can_pin_messages: allowed,
+            can_manage_topics: allowed,
+        }
+    }
+}
+
+/// `getChat` for the default permissions of the members, `telegram_types` doesn't know them.
+#[derive(Serialize, Debug, Clone)]
+pub struct GetChatPermissions {
+    pub chat_id: i64,
+}
+
+impl Method for GetChatPermissions {
+    const NAME: &'static str = "getChat";
+    type Item = ChatPermissionsInfo;
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChatPermissionsInfo {
+    /// Only returned for groups.
+    pub permissions: Option<ChatPermissions>,
+}
+
+/// Use this method to restrict a member of a supergroup, the bot has to be an administrator.
+#[derive(Serialize, Debug, Clone)]
+pub struct RestrictChatMember {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub permissions: ChatPermissions,
+    /// Unix time the restrictions are lifted, forever when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until_date: Option<i64>,
+}
+
+impl Method for RestrictChatMember {
+    const NAME: &'static str = "restrictChatMember";
+    type Item = bool;
+}
+
+/// Use this method to ban a member of a group, the bot has to be an administrator.
+#[derive(Serialize, Debug, Clone)]
+pub struct BanChatMember {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+impl Method for BanChatMember {
+    const NAME: &'static str = "banChatMember";
+    type Item = bool;
+}
+
+/// Use this method to unban a member, who can then join the group again.
+#[derive(Serialize, Debug, Clone)]
+pub struct UnbanChatMember {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub only_if_banned: bool,
+}
+
+impl Method for UnbanChatMember {
+    const NAME: &'static str = "unbanChatMember";
+    type Item = bool;
+}
+
+/// Use this method to let a user who asked to join a group in.
+#[derive(Serialize, Debug, Clone)]
+pub struct ApproveChatJoinRequest {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+impl Method for ApproveChatJoinRequest {
+    const NAME: &'static str = "approveChatJoinRequest";
+    type Item = bool;
+}
+
+/// Use this method to turn down a request to join a group.
+#[derive(Serialize, Debug, Clone)]
+pub struct DeclineChatJoinRequest {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+impl Method for DeclineChatJoinRequest {
+    const NAME: &'static str = "declineChatJoinRequest";
+    type Item = bool;
+}
+
+/// Use this method to change the title of a group, the bot has to be an administrator.
+#[derive(Serialize, Debug, Clone)]
+pub struct SetChatTitle {
+    pub chat_id: i64,
+    pub title: String,
+}
+
+impl Method for SetChatTitle {
+    const NAME: &'static str = "setChatTitle";
+    type Item = bool;
+}
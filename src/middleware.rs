@@ -0,0 +1,202 @@
+//! Middlewares wrapped around every command handler, see `Bot::with_middleware`.
+use std::cmp::Ordering;
+
+use telegram_types::bot::types::Message;
+use worker::Error as WorkerError;
+
+use crate::bot::{Bot, Next};
+use crate::command::{self, return_reply_message};
+use crate::credits;
+use crate::i18n;
+use crate::moderation;
+use crate::platform::{self, Env};
+use crate::reply::Reply;
+use crate::{console_error, console_log};
+
+/// Chats allowed to use the bot, comma separated.
+const VAR_ALLOWED_CHATS: &str = "ALLOWED_CHATS";
+/// Chat allowed when `ALLOWED_CHATS` is unset.
+const DEFAULT_ALLOWED_CHAT: i64 = 374506773;
+/// Commands other chats may use with `PAID_ACCESS`, besides messages to the default handler.
+const PAID_COMMANDS: &[&str] = &["chat", "image", "buy", "credits", "refund", "help"];
+/// Messages a chat may send per minute, unlimited when unset.
+const VAR_RATE_LIMIT: &str = "RATE_LIMIT";
+/// Seconds a per-minute counter is kept, KV doesn't allow less than a minute.
+const RATE_LIMIT_TTL: u64 = 120;
+
+/// Pick the language of the replies, see `i18n::language`.
+pub async fn locale<'a>(
+    m: Message,
+    env: Env,
+    mut bot: Bot<'a>,
+    next: Next<'a>,
+) -> Result<Reply, WorkerError> {
+    bot.lang = i18n::language(&m, &env, &bot).await?;
+    next.run(m, env, bot).await
+}
+
+/// Log every handled message with the time it took.
+pub async fn log<'a>(
+    m: Message,
+    env: Env,
+    bot: Bot<'a>,
+    next: Next<'a>,
+) -> Result<Reply, WorkerError> {
+    let command = next.command.clone().unwrap_or("<default>".to_string());
+    let chat_id = m.chat.id.0;
+    let start = platform::now_millis();
+    let result = next.run(m, env, bot).await;
+    let elapsed = platform::now_millis() - start;
+    match &result {
+        Ok(_) => console_log!("{} in chat {} took {}ms", command, chat_id, elapsed),
+        Err(err) => console_error!(
+            "{} in chat {} failed after {}ms: {}",
+            command,
+            chat_id,
+            elapsed,
+            err
+        ),
+    }
+    result
+}
+
+/// Tell the user a handler failed instead of leaving the message unanswered.
+///
+/// The error is only logged, it may carry details of KV, requests or upstream APIs.
+pub async fn errors<'a>(
+    m: Message,
+    env: Env,
+    bot: Bot<'a>,
+    next: Next<'a>,
+) -> Result<Reply, WorkerError> {
+    let message = m.clone();
+    let lang = bot.lang;
+    match next.run(m, env, bot).await {
+        Ok(reply) => Ok(reply),
+        Err(err) => {
+            console_error!(
+                "Message {} in chat {} failed: {}",
+                message.message_id.0,
+                message.chat.id.0,
+                err
+            );
+            return_reply_message(&message, i18n::text(lang, "something_went_wrong"))
+        }
+    }
+}
+
+/// Whether the chat may use the bot, see `ALLOWED_CHATS`.
+pub fn is_allowed(bot: &Bot<'_>, env: &Env, chat_id: i64) -> bool {
+    match bot.var(env, VAR_ALLOWED_CHATS) {
+        Some(chats) => chats
+            .split(',')
+            .any(|chat| chat.trim().parse() == Ok(chat_id)),
+        None => chat_id == DEFAULT_ALLOWED_CHAT,
+    }
+}
+
+/// Ignore messages from chats that are not allowed, unless they may pay with credits for one of
+/// `PAID_COMMANDS`.
+pub async fn auth<'a>(
+    m: Message,
+    env: Env,
+    bot: Bot<'a>,
+    next: Next<'a>,
+) -> Result<Reply, WorkerError> {
+    if !is_allowed(&bot, &env, m.chat.id.0) {
+        let paid = credits::paid_access(&bot, &env)
+            && next
+                .command
+                .as_deref()
+                .is_none_or(|command| PAID_COMMANDS.contains(&command));
+        if !paid {
+            console_log!("Chat {} is not allowed, ignoring...", m.chat.id.0);
+            return Ok(Reply::none());
+        }
+    }
+    next.run(m, env, bot).await
+}
+
+/// Check messages of members not trusted yet in groups with moderation on.
+pub async fn moderate<'a>(
+    m: Message,
+    env: Env,
+    bot: Bot<'a>,
+    next: Next<'a>,
+) -> Result<Reply, WorkerError> {
+    match moderation::check(&m, &env, &bot).await {
+        Ok(true) => return Ok(Reply::none()),
+        Ok(false) => {}
+        // a failing model lets messages through rather than blocking the group
+        Err(err) => console_error!("Failed to moderate message {}: {}", m.message_id.0, err),
+    }
+    next.run(m, env, bot).await
+}
+
+fn rate_limit_key(chat_id: i64) -> String {
+    let minute = platform::now_millis() / 60_000;
+    format!("INDEX_RATE_LIMIT:{}:{}", chat_id, minute)
+}
+
+/// Messages of the chat counted this minute and the limit, `None` when unlimited.
+pub async fn rate_limit_state(
+    bot: &Bot<'_>,
+    env: &Env,
+    chat_id: i64,
+) -> Result<Option<(u64, u64)>, WorkerError> {
+    let Some(limit) = bot
+        .var(env, VAR_RATE_LIMIT)
+        .and_then(|limit| limit.parse::<u64>().ok())
+    else {
+        return Ok(None);
+    };
+    let count = bot
+        .store(env)?
+        .get(&rate_limit_key(chat_id))
+        .text()
+        .await?
+        .and_then(|count| count.parse::<u64>().ok())
+        .unwrap_or_default();
+    Ok(Some((count, limit)))
+}
+
+/// Count a message of the chat, the count compared to the limit or `None` when unlimited.
+///
+/// With `RATE_LIMIT` set, every counted message costs a KV read and a KV write for its counter.
+pub async fn count_message(
+    bot: &Bot<'_>,
+    env: &Env,
+    chat_id: i64,
+) -> Result<Option<Ordering>, WorkerError> {
+    let Some((count, limit)) = rate_limit_state(bot, env, chat_id).await? else {
+        return Ok(None);
+    };
+    // KV isn't atomic, the count is best effort
+    bot.store(env)?
+        .put(&rate_limit_key(chat_id), (count + 1).to_string())?
+        .expiration_ttl(RATE_LIMIT_TTL)
+        .execute()
+        .await?;
+    Ok(Some(count.cmp(&limit)))
+}
+
+/// Limit how many messages of a chat are handled per minute, see `count_message`. Group chatter
+/// isn't answered, so it isn't counted either.
+pub async fn rate_limit<'a>(
+    m: Message,
+    env: Env,
+    bot: Bot<'a>,
+    next: Next<'a>,
+) -> Result<Reply, WorkerError> {
+    if next.command.is_none() && command::is_chatter(&m, &env, &bot).await? {
+        return next.run(m, env, bot).await;
+    }
+    match count_message(&bot, &env, m.chat.id.0).await? {
+        None | Some(Ordering::Less) => next.run(m, env, bot).await,
+        // only the first message over the limit gets an answer
+        Some(Ordering::Equal) => {
+            return_reply_message(&m, i18n::text(bot.lang, "too_many_messages"))
+        }
+        Some(Ordering::Greater) => Ok(Reply::none()),
+    }
+}
@@ -0,0 +1,349 @@
+//! Messages of new members of groups checked by the chat model against the policy of the group.
+use serde::{Deserialize, Serialize};
+use telegram_types::bot::methods::{
+    ChatTarget, DeleteMessage, EditMessageText, ForwardMessage, GetChatAdministrators, ReplyMarkup,
+    SendMessage,
+};
+use telegram_types::bot::types::{
+    CallbackQuery, ChatId, InlineKeyboardButton, InlineKeyboardButtonPressed, InlineKeyboardMarkup,
+    Message, UserId,
+};
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::chat::add_chat_usage;
+use crate::command::openai_config;
+use crate::i18n::{self, Lang};
+use crate::methods::{AnswerCallbackQuery, ChatPermissions, RestrictChatMember};
+use crate::openai;
+use crate::platform::Env;
+use crate::reminder;
+use crate::reply::Reply;
+use crate::{console_error, console_log};
+
+/// Prefix of the callback data of the appeal button.
+pub const CALLBACK_PREFIX: &str = "appeal";
+pub const ACTIONS: &[&str] = &["off", "warn", "delete", "mute", "report"];
+/// Messages a member sends before they are trusted and no longer checked.
+const VAR_TRUSTED_MESSAGES: &str = "MODERATION_TRUSTED_MESSAGES";
+const DEFAULT_TRUSTED_MESSAGES: u64 = 10;
+const VAR_MODERATION_MODEL: &str = "MODERATION_MODEL";
+const DEFAULT_POLICY: &str = "No spam, scams, advertising or unsolicited links.";
+/// Seconds a member is muted for.
+const MUTE_DURATION: i64 = 24 * 60 * 60;
+/// Seconds a flagged message can be appealed.
+const FLAGGED_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Moderation of a group, set with `/set_moderation`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Moderation {
+    /// One of `ACTIONS` but `off`.
+    pub action: String,
+    pub policy: Option<String>,
+}
+
+/// A message taken for a violation, kept with the notice about it for appeals.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Flagged {
+    pub action: String,
+    pub user_id: i64,
+    pub user_name: String,
+    pub text: String,
+}
+
+fn moderation_key(chat_id: i64) -> String {
+    format!("INDEX_CHAT_MODERATION:{}", chat_id)
+}
+
+fn trust_key(chat_id: i64, user_id: i64) -> String {
+    format!("INDEX_TRUST:{}:{}", chat_id, user_id)
+}
+
+fn flagged_key(chat_id: i64, notice_id: i64) -> String {
+    format!("INDEX_FLAGGED:{}:{}", chat_id, notice_id)
+}
+
+pub async fn get_moderation(
+    env: &Env,
+    bot: &Bot<'_>,
+    chat_id: i64,
+) -> Result<Option<Moderation>, WorkerError> {
+    bot.store(env)?
+        .get(&moderation_key(chat_id))
+        .json::<Moderation>()
+        .await
+}
+
+/// Set the moderation of a group, `None` to turn it off.
+pub async fn set_moderation(
+    env: &Env,
+    bot: &Bot<'_>,
+    chat_id: i64,
+    moderation: Option<Moderation>,
+) -> Result<(), WorkerError> {
+    let store = bot.store(env)?;
+    match moderation {
+        Some(moderation) => {
+            store
+                .put(&moderation_key(chat_id), moderation)?
+                .execute()
+                .await?
+        }
+        None => store.delete(&moderation_key(chat_id)).await?,
+    }
+    Ok(())
+}
+
+fn trusted_messages(env: &Env, bot: &Bot<'_>) -> u64 {
+    bot.var(env, VAR_TRUSTED_MESSAGES)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(DEFAULT_TRUSTED_MESSAGES)
+}
+
+fn system_prompt(policy: &str) -> String {
+    format!(
+        "You moderate a Telegram group with these rules:\n{}\n\
+         Reply with only VIOLATION if the next message breaks the rules, or OK if it doesn't.",
+        policy
+    )
+}
+
+fn is_violation(answer: &str) -> bool {
+    answer.trim().to_uppercase().starts_with("VIOLATION")
+}
+
+/// Check a message of a member who isn't trusted yet, `true` when it was taken for a violation.
+pub async fn check(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<bool, WorkerError> {
+    let (Some(user), Some(text)) = (&m.from, m.text.as_ref().or(m.caption.as_ref())) else {
+        return Ok(false);
+    };
+    let Some(moderation) = get_moderation(env, bot, m.chat.id.0).await? else {
+        return Ok(false);
+    };
+    let store = bot.store(env)?;
+    let key = trust_key(m.chat.id.0, user.id.0);
+    let trust = store
+        .get(&key)
+        .text()
+        .await?
+        .and_then(|trust| trust.parse::<u64>().ok())
+        .unwrap_or_default();
+    if trust >= trusted_messages(env, bot) {
+        return Ok(false);
+    }
+    let policy = moderation.policy.as_deref().unwrap_or(DEFAULT_POLICY);
+    let msgs = [
+        openai::Message::new("system", &system_prompt(policy)),
+        openai::Message::new("user", text),
+    ];
+    let (api_key, endpoint) = openai_config(m, env, bot).await?;
+    let model = bot
+        .var(env, VAR_MODERATION_MODEL)
+        .unwrap_or(openai::DEFAULT_CHAT_MODEL.to_string());
+    let (answer, usage) = openai::chat_completion(&msgs, api_key, endpoint, &model).await?;
+    add_chat_usage(m, env, bot, &usage).await?;
+    // admins aren't moderated, only asked about when a message looks bad
+    if !is_violation(&answer) || bot.is_admin(ChatTarget::Id(m.chat.id), user.id).await? {
+        store.put(&key, (trust + 1).to_string())?.execute().await?;
+        return Ok(false);
+    }
+    console_log!(
+        "Message {} of {} in {} breaks the rules, {}",
+        m.message_id.0,
+        user.id.0,
+        m.chat.id.0,
+        moderation.action
+    );
+    let flagged = Flagged {
+        action: moderation.action,
+        user_id: user.id.0,
+        user_name: user.first_name.clone(),
+        text: text.clone(),
+    };
+    take_action(m, env, bot, flagged).await?;
+    Ok(true)
+}
+
+fn appeal_button(lang: Lang, user_id: i64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![InlineKeyboardButton {
+            text: i18n::text(lang, "appeal").to_string(),
+            pressed: InlineKeyboardButtonPressed::CallbackData(format!(
+                "{}:{}",
+                CALLBACK_PREFIX, user_id
+            )),
+        }]],
+    }
+}
+
+async fn take_action(
+    m: &Message,
+    env: &Env,
+    bot: &Bot<'_>,
+    flagged: Flagged,
+) -> Result<(), WorkerError> {
+    let (chat_id, lang) = (m.chat.id, bot.lang);
+    if flagged.action == "report" {
+        return report(m, bot, &flagged).await;
+    }
+    if flagged.action != "warn" {
+        let delete = DeleteMessage {
+            chat_id: ChatTarget::Id(chat_id),
+            message_id: m.message_id,
+        };
+        if let Err(err) = bot.call_method(delete).await {
+            console_error!("Failed to delete message {}: {}", m.message_id.0, err);
+        }
+    }
+    if flagged.action == "mute" {
+        bot.call_method(RestrictChatMember {
+            chat_id: chat_id.0,
+            user_id: flagged.user_id,
+            permissions: ChatPermissions::all(false),
+            until_date: Some(reminder::now() + MUTE_DURATION),
+        })
+        .await?;
+    }
+    let notice = format!("notice_{}", flagged.action);
+    let notice = i18n::format(lang, &notice, &[&flagged.user_name]);
+    let mut message = SendMessage::new(ChatTarget::Id(chat_id), notice.as_str());
+    if flagged.action == "warn" {
+        message = message.reply(m.message_id);
+    }
+    message.reply_markup = Some(ReplyMarkup::InlineKeyboard(appeal_button(
+        lang,
+        flagged.user_id,
+    )));
+    let notice = bot.send_message(message).await?;
+    bot.store(env)?
+        .put(&flagged_key(chat_id.0, notice.message_id.0), flagged)?
+        .expiration_ttl(FLAGGED_TTL)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+// admins who never started a private chat with the bot can't be reported to
+async fn report(m: &Message, bot: &Bot<'_>, flagged: &Flagged) -> Result<(), WorkerError> {
+    let admins = bot
+        .call_method(GetChatAdministrators {
+            chat_id: ChatTarget::Id(m.chat.id),
+        })
+        .await?;
+    let text = i18n::format(bot.lang, "report", &[&flagged.user_name]);
+    for admin in admins.iter().filter(|admin| !admin.user.is_bot) {
+        let admin_chat = ChatTarget::Id(ChatId(admin.user.id.0));
+        let forward = ForwardMessage {
+            chat_id: admin_chat.clone(),
+            from_chat_id: ChatTarget::Id(m.chat.id),
+            message_id: m.message_id,
+        };
+        let result = match bot.call_method(forward).await {
+            Ok(forwarded) => {
+                let message = SendMessage::new(admin_chat, text.as_str());
+                bot.send_message(message.reply(forwarded.message_id))
+                    .await
+                    .map(|_| ())
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            console_error!("Failed to report to admin {}: {}", admin.user.id.0, err);
+        }
+    }
+    Ok(())
+}
+
+/// An appeal against a notice, pressed by the member asks the admins, by an admin undoes it.
+pub async fn handle_callback(
+    query: &CallbackQuery,
+    data: &str,
+    env: &Env,
+    bot: &Bot<'_>,
+) -> Result<Reply, WorkerError> {
+    let lang = query
+        .from
+        .language_code
+        .as_deref()
+        .and_then(Lang::from_code)
+        .unwrap_or_default();
+    let answer = |key: &str| {
+        Reply::call(AnswerCallbackQuery::new(
+            &query.id,
+            Some(i18n::text(lang, key).to_string()),
+        ))
+    };
+    let (Some(notice), Ok(user_id)) = (&query.message, data.parse::<i64>()) else {
+        return Ok(answer("invalid_input"));
+    };
+    let (chat_id, notice_id) = (notice.chat.id, notice.message_id);
+    let store = bot.store(env)?;
+    let key = flagged_key(chat_id.0, notice_id.0);
+    let Some(flagged) = store.get(&key).json::<Flagged>().await? else {
+        return Ok(answer("appeal_expired"));
+    };
+    let is_admin = bot
+        .is_admin(ChatTarget::Id(chat_id), UserId(query.from.id.0))
+        .await?;
+    if !is_admin {
+        if query.from.id.0 != user_id {
+            return Ok(answer("appeal_not_yours"));
+        }
+        let text = notice.text.clone().unwrap_or_default();
+        let appealed = i18n::text(lang, "appealed_notice");
+        // pressed again after appealing
+        if text.ends_with(appealed.trim_start()) {
+            return Ok(answer("appeal_sent"));
+        }
+        let text = text + appealed;
+        let edit = EditMessageText::new(ChatTarget::Id(chat_id), notice_id, text.as_str())
+            .reply_markup(appeal_button(lang, user_id));
+        return Ok(answer("appeal_sent").then(edit));
+    }
+    // a muted member gets the default permissions of the group back, fetched before the flag is
+    // dropped so the appeal can be accepted again when it fails
+    let permissions = match flagged.action == "mute" {
+        true => Some(bot.default_permissions(chat_id.0).await?),
+        false => None,
+    };
+    store.delete(&key).await?;
+    // the member is trusted from now on
+    store
+        .put(
+            &trust_key(chat_id.0, user_id),
+            trusted_messages(env, bot).to_string(),
+        )?
+        .execute()
+        .await?;
+    let mut reply = answer("appeal_accepted").edit(
+        chat_id,
+        notice_id,
+        i18n::format(lang, "appeal_accepted_notice", &[&flagged.user_name]),
+    );
+    if let Some(permissions) = permissions {
+        reply = reply.then(RestrictChatMember {
+            chat_id: chat_id.0,
+            user_id,
+            permissions,
+            until_date: None,
+        });
+    }
+    if flagged.action != "warn" {
+        let text = i18n::format(
+            lang,
+            "restored_message",
+            &[&flagged.user_name, &flagged.text],
+        );
+        reply = reply.send(SendMessage::new(ChatTarget::Id(chat_id), text.as_str()));
+    }
+    Ok(reply)
+}
+
+#[test]
+fn test_is_violation() {
+    assert!(is_violation("VIOLATION"));
+    assert!(is_violation(" violation: the message advertises a casino"));
+    assert!(!is_violation("OK"));
+    assert!(!is_violation("The message is fine."));
+    assert!(system_prompt("No politics.").contains("No politics."));
+}
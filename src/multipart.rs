@@ -0,0 +1,67 @@
+//! Minimal `multipart/form-data` encoder, `worker::FormData` can't carry files yet.
+
+const BOUNDARY: &str = "----fdkevin-bot-form-boundary";
+
+#[derive(Default)]
+pub struct Form {
+    body: Vec<u8>,
+}
+
+impl Form {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text<S: AsRef<str>>(mut self, name: &str, value: S) -> Self {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY,
+                name,
+                value.as_ref()
+            )
+            .as_bytes(),
+        );
+        self
+    }
+
+    pub fn file(mut self, name: &str, file_name: &str, mime_type: &str, data: &[u8]) -> Self {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                BOUNDARY, name, file_name, mime_type
+            )
+            .as_bytes(),
+        );
+        self.body.extend_from_slice(data);
+        self.body.extend_from_slice(b"\r\n");
+        self
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", BOUNDARY)
+    }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        self.body
+    }
+}
+
+#[test]
+fn test_form_encoding() {
+    let body = Form::new()
+        .text("model", "whisper-1")
+        .file("file", "voice.ogg", "audio/ogg", b"OggS")
+        .into_bytes();
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper-1\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"voice.ogg\"\r\n\
+             Content-Type: audio/ogg\r\n\r\nOggS\r\n--{b}--\r\n",
+            b = BOUNDARY
+        )
+    );
+}
@@ -1,36 +1,125 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
-use worker::{console_log, wasm_bindgen::JsValue, Headers, Request, RequestInit};
+
+use crate::console_log;
+use crate::multipart::Form;
+use crate::platform;
+
+pub const DEFAULT_CHAT_MODEL: &str = "gpt-3.5-turbo-0301";
+pub const DEFAULT_VISION_MODEL: &str = "gpt-4o";
+pub const DEFAULT_TRANSCRIPTION_MODEL: &str = "whisper-1";
+pub const DEFAULT_IMAGE_MODEL: &str = "dall-e-3";
+
+const DEFAULT_API_BASE: &str = "https://api.openai.com/v1";
+const CHAT_COMPLETIONS_PATH: &str = "/chat/completions";
+
+// configured endpoints point at chat completions, derive other apis from them
+// an endpoint they can't be derived from is an error, the key of the chat is meant for it only
+pub fn api_url(endpoint: &Option<String>, path: &str) -> Result<String, worker::Error> {
+    let base = match endpoint {
+        Some(endpoint) => endpoint
+            .strip_suffix(CHAT_COMPLETIONS_PATH)
+            .ok_or_else(|| {
+                worker::Error::RustError(format!(
+                    "The endpoint {} doesn't end in {}, so {} can't be found next to it",
+                    endpoint, CHAT_COMPLETIONS_PATH, path
+                ))
+            })?,
+        None => DEFAULT_API_BASE,
+    };
+    Ok(format!("{}{}", base, path))
+}
+
+/// Chat completions endpoint used with a configured one, the OpenAI one by default.
+pub fn chat_endpoint(endpoint: Option<String>) -> String {
+    endpoint.unwrap_or(format!("{}{}", DEFAULT_API_BASE, CHAT_COMPLETIONS_PATH))
+}
 
 // openai chat api
 pub async fn call_chat_api(
-    msgs: &Vec<Message>,
+    msgs: &[Message],
     key: String,
     endpoint: Option<String>,
+    model: &str,
+) -> Result<String, worker::Error> {
+    Ok(chat_completion(msgs, key, endpoint, model).await?.0)
+}
+
+/// POST `body` with the key as both OpenAI and Azure expect it, the response is returned as text.
+async fn post(
+    url: &str,
+    key: &str,
+    content_type: &str,
+    body: Vec<u8>,
 ) -> Result<String, worker::Error> {
-    let mut headers = Headers::new();
-    headers.set("Authorization", format!("Bearer {}", key).as_str())?;
-    headers.set("api-key", key.as_str())?;
-    headers.set("Content-Type", "application/json")?;
+    let authorization = format!("Bearer {}", key);
+    let headers = [
+        ("Authorization", authorization.as_str()),
+        ("api-key", key),
+        ("Content-Type", content_type),
+    ];
+    let resp = platform::fetch(worker::Method::Post, url, &headers, Some(body)).await?;
+    let resp_text = resp.text()?;
+    console_log!("{}", &resp_text);
+    Ok(resp_text)
+}
+
+/// The answer of the chat model with the tokens it took.
+pub async fn chat_completion(
+    msgs: &[Message],
+    key: String,
+    endpoint: Option<String>,
+    model: &str,
+) -> Result<(String, Usage), worker::Error> {
     let body = ChatRequest {
-        model: "gpt-3.5-turbo-0301".to_string(),
-        messages: msgs.clone(),
+        model: model.to_string(),
+        messages: msgs.to_vec(),
     };
-    console_log!("{:?}", body);
-    let req = Request::new_with_init(
-        endpoint
-            .unwrap_or("https://api.openai.com/v1/chat/completions".to_string())
-            .as_str(),
-        RequestInit::new()
-            .with_method(worker::Method::Post)
-            .with_headers(headers)
-            .with_body(Some(JsValue::from_str(&serde_json::to_string(&body)?))),
-    )?;
-    console_log!("{:?}", &req.headers());
-    let mut resp = worker::Fetch::Request(req).send().await?;
-    let resp_text = resp.text().await?;
-    console_log!("{}", &resp_text);
+    // the messages may carry whole images as data URLs
+    console_log!(
+        "Calling {} with {} messages",
+        body.model,
+        body.messages.len()
+    );
+    let resp_text = post(
+        &chat_endpoint(endpoint),
+        &key,
+        "application/json",
+        serde_json::to_vec(&body)?,
+    )
+    .await?;
     match serde_json::from_str::<ChatResponse>(&resp_text) {
-        Ok(msgs) => Ok(msgs.choices[0].message.content.to_string()),
+        Ok(msgs) => match msgs.choices.first() {
+            Some(choice) => Ok((choice.message.content.text(), msgs.usage)),
+            None => Err(worker::Error::from("The model returned no answer")),
+        },
+        Err(_) => {
+            let err_resp = serde_json::from_str::<ErrorResponse>(&resp_text)?;
+            Err(worker::Error::from(err_resp.error.message))
+        }
+    }
+}
+
+// openai audio transcription api
+pub async fn transcribe(
+    data: &[u8],
+    file_name: &str,
+    mime_type: &str,
+    key: String,
+    endpoint: Option<String>,
+) -> Result<String, worker::Error> {
+    let form = Form::new()
+        .text("model", DEFAULT_TRANSCRIPTION_MODEL)
+        .file("file", file_name, mime_type, data);
+    let resp_text = post(
+        &api_url(&endpoint, "/audio/transcriptions")?,
+        &key,
+        &form.content_type(),
+        form.into_bytes(),
+    )
+    .await?;
+    match serde_json::from_str::<TranscriptionResponse>(&resp_text) {
+        Ok(transcription) => Ok(transcription.text),
         Err(_) => {
             let err_resp = serde_json::from_str::<ErrorResponse>(&resp_text)?;
             Err(worker::Error::from(err_resp.error.message))
@@ -38,6 +127,70 @@ pub async fn call_chat_api(
     }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TranscriptionResponse {
+    text: String,
+}
+
+// openai image generation api
+pub async fn generate_images(
+    request: &ImageRequest,
+    key: String,
+    endpoint: Option<String>,
+) -> Result<Vec<Image>, worker::Error> {
+    console_log!("{:?}", request);
+    let resp_text = post(
+        &api_url(&endpoint, "/images/generations")?,
+        &key,
+        "application/json",
+        serde_json::to_vec(request)?,
+    )
+    .await?;
+    match serde_json::from_str::<ImageResponse>(&resp_text) {
+        Ok(images) => Ok(images.data),
+        Err(_) => {
+            let err_resp = serde_json::from_str::<ErrorResponse>(&resp_text)?;
+            Err(worker::Error::from(err_resp.error.message))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageRequest {
+    pub model: String,
+    pub prompt: String,
+    pub n: u8,
+    pub size: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+    pub response_format: String,
+}
+
+impl ImageRequest {
+    pub fn new(prompt: &str) -> Self {
+        ImageRequest {
+            model: DEFAULT_IMAGE_MODEL.to_string(),
+            prompt: prompt.to_string(),
+            n: 1,
+            size: "1024x1024".to_string(),
+            quality: None,
+            response_format: "url".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImageResponse {
+    created: i64,
+    data: Vec<Image>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Image {
+    pub url: String,
+    pub revised_prompt: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct ErrorResponse {
     error: Error,
@@ -61,24 +214,115 @@ pub struct ChatRequest {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    pub content: Content,
 }
 
 impl Message {
     pub fn new(role: &str, content: &str) -> Self {
         Message {
             role: role.to_string(),
-            content: content.to_string(),
+            content: Content::Text(content.to_string()),
+        }
+    }
+
+    pub fn with_parts(role: &str, parts: Vec<ContentPart>) -> Self {
+        Message {
+            role: role.to_string(),
+            content: Content::Parts(parts),
+        }
+    }
+
+    pub fn has_image(&self) -> bool {
+        match &self.content {
+            Content::Text(_) => false,
+            Content::Parts(parts) => parts
+                .iter()
+                .any(|part| matches!(part, ContentPart::ImageUrl { .. })),
+        }
+    }
+
+    // image payloads are too large to keep around, leave a marker instead
+    pub fn without_images(self) -> Self {
+        match self.content {
+            Content::Text(_) => self,
+            Content::Parts(parts) => {
+                let text = parts
+                    .iter()
+                    .map(|part| match part {
+                        ContentPart::Text { text } => text.as_str(),
+                        ContentPart::ImageUrl { .. } => "[image]",
+                    })
+                    .collect::<Vec<&str>>()
+                    .join("\n");
+                Message::new(&self.role, &text)
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+impl ContentPart {
+    pub fn text(text: &str) -> Self {
+        ContentPart::Text {
+            text: text.to_string(),
+        }
+    }
+
+    // inline the image as a data url, file urls would leak the bot token
+    pub fn image(mime_type: &str, data: &[u8]) -> Self {
+        ContentPart::ImageUrl {
+            image_url: ImageUrl {
+                url: format!("data:{};base64,{}", mime_type, BASE64.encode(data)),
+                detail: None,
+            },
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatResponse {
     id: String,
     object: String,
     created: i64,
     model: String,
+    // some compatible endpoints leave it out
+    #[serde(default)]
     usage: Usage,
     choices: Vec<Choice>,
 }
@@ -90,9 +334,49 @@ pub struct Choice {
     index: i64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct Usage {
-    prompt_tokens: i64,
-    completion_tokens: i64,
-    total_tokens: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+#[test]
+fn test_message_without_images() {
+    let legacy = serde_json::from_str::<Message>(r#"{"role":"user","content":"hi"}"#).unwrap();
+    assert_eq!(legacy.content.text(), "hi");
+    let msg = Message::with_parts(
+        "user",
+        vec![
+            ContentPart::text("what is this"),
+            ContentPart::image("image/png", b"png"),
+        ],
+    );
+    assert!(msg.has_image());
+    let stripped = msg.without_images();
+    assert!(!stripped.has_image());
+    assert_eq!(stripped.content.text(), "what is this\n[image]");
+}
+
+#[test]
+fn test_api_url() {
+    let url =
+        |endpoint: Option<&str>| api_url(&endpoint.map(str::to_string), "/audio/transcriptions");
+    assert_eq!(
+        url(None).unwrap(),
+        "https://api.openai.com/v1/audio/transcriptions"
+    );
+    assert_eq!(
+        url(Some("https://example.com/openai/v1/chat/completions")).unwrap(),
+        "https://example.com/openai/v1/audio/transcriptions"
+    );
+    assert!(url(Some("https://example.com/v1/messages")).is_err());
 }
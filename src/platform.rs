@@ -0,0 +1,210 @@
+//! What the bot needs from its host: vars, KV, HTTP, time and logs.
+//!
+//! Deployed, all of it comes from Cloudflare Workers. The local runner in `src/bin/poll.rs`
+//! provides vars from an env file, a KV store kept in files and blocking HTTP instead.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use worker::wasm_bindgen::{JsCast, JsValue};
+use worker::{Error as WorkerError, Method};
+
+use crate::store::{Kv, LocalKv};
+
+/// `worker::console_log!` in the worker, standard error in the local runner.
+#[macro_export]
+macro_rules! console_log {
+    ($($t:tt)*) => {
+        if cfg!(target_arch = "wasm32") {
+            ::worker::console_log!($($t)*)
+        } else {
+            eprintln!($($t)*)
+        }
+    };
+}
+
+/// `worker::console_debug!` in the worker, standard error in the local runner.
+#[macro_export]
+macro_rules! console_debug {
+    ($($t:tt)*) => {
+        if cfg!(target_arch = "wasm32") {
+            ::worker::console_debug!($($t)*)
+        } else {
+            eprintln!($($t)*)
+        }
+    };
+}
+
+/// `worker::console_error!` in the worker, standard error in the local runner.
+#[macro_export]
+macro_rules! console_error {
+    ($($t:tt)*) => {
+        if cfg!(target_arch = "wasm32") {
+            ::worker::console_error!($($t)*)
+        } else {
+            eprintln!($($t)*)
+        }
+    };
+}
+
+/// Vars, secrets and KV namespaces of the bot.
+#[derive(Clone)]
+pub enum Env {
+    Worker(Rc<worker::Env>),
+    Local(Rc<LocalEnv>),
+}
+
+/// Environment of the local runner, see `Env::local`.
+pub struct LocalEnv {
+    vars: HashMap<String, String>,
+    /// Directory the KV namespaces are kept in, one JSON file each. `None` keeps them in memory.
+    kv_dir: Option<PathBuf>,
+    namespaces: RefCell<HashMap<String, LocalKv>>,
+}
+
+impl From<&worker::Env> for Env {
+    fn from(env: &worker::Env) -> Self {
+        Env::Worker(Rc::new(JsValue::clone(env).unchecked_into()))
+    }
+}
+
+impl Env {
+    /// Environment with the given vars, which are also the secrets, and KV namespaces kept in
+    /// `kv_dir`.
+    pub fn local(vars: HashMap<String, String>, kv_dir: Option<PathBuf>) -> Self {
+        Env::Local(Rc::new(LocalEnv {
+            vars,
+            kv_dir,
+            namespaces: RefCell::new(HashMap::new()),
+        }))
+    }
+
+    pub fn var(&self, name: &str) -> Result<String, WorkerError> {
+        match self {
+            Env::Worker(env) => Ok(env.var(name)?.to_string()),
+            Env::Local(env) => env
+                .vars
+                .get(name)
+                .cloned()
+                .ok_or_else(|| WorkerError::BindingError(name.to_string())),
+        }
+    }
+
+    pub fn secret(&self, name: &str) -> Result<String, WorkerError> {
+        match self {
+            Env::Worker(env) => Ok(env.secret(name)?.to_string()),
+            Env::Local(_) => self.var(name),
+        }
+    }
+
+    pub fn kv(&self, binding: &str) -> Result<Kv, WorkerError> {
+        match self {
+            Env::Worker(env) => Ok(Kv::Worker(env.kv(binding)?)),
+            Env::Local(env) => {
+                let mut namespaces = env.namespaces.borrow_mut();
+                let kv = namespaces.entry(binding.to_string()).or_insert_with(|| {
+                    let file = env
+                        .kv_dir
+                        .as_ref()
+                        .map(|dir| dir.join(format!("{}.json", binding)));
+                    LocalKv::open(file)
+                });
+                Ok(Kv::Local(kv.clone()))
+            }
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    if cfg!(target_arch = "wasm32") {
+        worker::Date::now().as_millis()
+    } else {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64)
+    }
+}
+
+/// Status and body of an HTTP response, read in full.
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn text(&self) -> Result<String, WorkerError> {
+        String::from_utf8(self.body.clone()).map_err(|_| WorkerError::BadEncoding)
+    }
+
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, WorkerError> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Send an HTTP request, with `fetch` in the worker and blocking in the local runner.
+pub async fn fetch(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<Vec<u8>>,
+) -> Result<HttpResponse, WorkerError> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return local_fetch(method, url, headers, body);
+    #[cfg(target_arch = "wasm32")]
+    worker_fetch(method, url, headers, body).await
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+async fn worker_fetch(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<Vec<u8>>,
+) -> Result<HttpResponse, WorkerError> {
+    let mut request_headers = worker::Headers::new();
+    for (name, value) in headers {
+        request_headers.set(name, value)?;
+    }
+    let mut init = worker::RequestInit::new();
+    init.with_method(method).with_headers(request_headers);
+    if let Some(body) = body {
+        init.with_body(Some(
+            worker::js_sys::Uint8Array::from(body.as_slice()).into(),
+        ));
+    }
+    let request = worker::Request::new_with_init(url, &init)?;
+    let mut response = worker::Fetch::Request(request).send().await?;
+    Ok(HttpResponse {
+        status: response.status_code(),
+        body: response.bytes().await?,
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn local_fetch(
+    method: Method,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<Vec<u8>>,
+) -> Result<HttpResponse, WorkerError> {
+    use std::io::Read;
+
+    let mut request = ureq::request(method.as_ref(), url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let result = match body {
+        Some(body) => request.send_bytes(&body),
+        None => request.call(),
+    };
+    let response = match result {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => return Err(WorkerError::RustError(err.to_string())),
+    };
+    let status = response.status();
+    let mut body = vec![];
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(HttpResponse { status, body })
+}
@@ -0,0 +1,127 @@
+//! Sync of the bot profile: command lists, name, descriptions and the menu button.
+use std::collections::BTreeSet;
+
+use telegram_types::bot::methods::Method;
+
+use crate::bot::Bot;
+use crate::methods::{
+    BotCommand, BotCommandScope, SetChatMenuButton, SetMyCommands, SetMyDescription, SetMyName,
+    SetMyShortDescription,
+};
+use crate::platform::Env;
+use crate::webapp;
+
+/// Extra profile languages, comma separated, e.g. `zh,ja`.
+///
+/// The name and descriptions of a language are read from vars with its upper-cased code as suffix,
+/// e.g. `BOT_DESCRIPTION_ZH`.
+const VAR_LANGUAGES: &str = "BOT_LANGUAGES";
+const VAR_NAME: &str = "BOT_NAME";
+const VAR_DESCRIPTION: &str = "BOT_DESCRIPTION";
+const VAR_SHORT_DESCRIPTION: &str = "BOT_SHORT_DESCRIPTION";
+/// Users allowed to sync the profile, comma separated user ids. Nobody when unset.
+const VAR_ADMINS: &str = "BOT_ADMINS";
+
+/// Commands of `scope` in the menu, described in the given language.
+pub fn bot_commands(
+    bot: &Bot<'_>,
+    scope: BotCommandScope,
+    language_code: Option<&str>,
+) -> Vec<BotCommand> {
+    bot.sorted_commands()
+        .into_iter()
+        .filter(|(_, info)| !info.hidden && scope.includes(info.scope))
+        .map(|(name, info)| BotCommand {
+            command: name.to_string(),
+            description: info.description_in(language_code).to_string(),
+        })
+        .collect()
+}
+
+fn languages(bot: &Bot<'_>, env: &Env) -> BTreeSet<String> {
+    let mut languages = bot
+        .var(env, VAR_LANGUAGES)
+        .unwrap_or_default()
+        .split(',')
+        .map(|code| code.trim().to_ascii_lowercase())
+        .filter(|code| !code.is_empty())
+        .collect::<BTreeSet<String>>();
+    for command in bot.commands.values() {
+        for (code, _) in &command.info.translations {
+            languages.insert(code.clone());
+        }
+    }
+    languages
+}
+
+async fn call<T: Method>(bot: &Bot<'_>, request: T, target: String, report: &mut Vec<String>) {
+    let result = match bot.call_method(request).await {
+        Ok(_) => "ok".to_string(),
+        Err(err) => err.to_string(),
+    };
+    report.push(format!("{} {}: {}", T::NAME, target, result));
+}
+
+/// Whether the user may change the profile of the bot, see `BOT_ADMINS`.
+pub fn is_bot_admin(bot: &Bot<'_>, env: &Env, user_id: i64) -> bool {
+    bot.var(env, VAR_ADMINS).is_some_and(|admins| {
+        admins
+            .split(',')
+            .any(|admin| admin.trim().parse() == Ok(user_id))
+    })
+}
+
+/// Push the commands and profile to Telegram, one report line per request.
+pub async fn sync(bot: &Bot<'_>, env: &Env) -> Vec<String> {
+    let mut report = vec![];
+    let languages = languages(bot, env);
+    let language_codes = [None]
+        .into_iter()
+        .chain(languages.iter().map(|code| Some(code.as_str())));
+    for language_code in language_codes {
+        let language = language_code.map_or(String::new(), |code| format!(" ({})", code));
+        for scope in BotCommandScope::ALL {
+            let request = SetMyCommands {
+                commands: bot_commands(bot, scope, language_code),
+                scope: Some(scope),
+                language_code: language_code.map(str::to_string),
+            };
+            let target = format!("{:?}{}", scope, language);
+            call(bot, request, target, &mut report).await;
+        }
+
+        let suffix = language_code.map_or(String::new(), |code| {
+            format!("_{}", code.to_ascii_uppercase())
+        });
+        let language_code = language_code.map(str::to_string);
+        if let Some(name) = bot.var(env, &format!("{}{}", VAR_NAME, suffix)) {
+            let request = SetMyName {
+                name,
+                language_code: language_code.clone(),
+            };
+            call(bot, request, language.clone(), &mut report).await;
+        }
+        if let Some(description) = bot.var(env, &format!("{}{}", VAR_DESCRIPTION, suffix)) {
+            let request = SetMyDescription {
+                description,
+                language_code: language_code.clone(),
+            };
+            call(bot, request, language.clone(), &mut report).await;
+        }
+        let var_short_description = format!("{}{}", VAR_SHORT_DESCRIPTION, suffix);
+        if let Some(short_description) = bot.var(env, &var_short_description) {
+            let request = SetMyShortDescription {
+                short_description,
+                language_code,
+            };
+            call(bot, request, language, &mut report).await;
+        }
+    }
+    let app_url = webapp::app_url(bot, env).await.unwrap_or_default();
+    let request = SetChatMenuButton {
+        chat_id: None,
+        menu_button: webapp::menu_button(app_url),
+    };
+    call(bot, request, String::new(), &mut report).await;
+    report
+}
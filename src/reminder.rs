@@ -0,0 +1,444 @@
+//! Reminders kept in KV and delivered by the scheduled handler.
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Timelike, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use telegram_types::bot::methods::{ChatTarget, SendMessage};
+use telegram_types::bot::types::{ChatId, Message};
+use worker::Error as WorkerError;
+
+use crate::args::parse_duration;
+use crate::bot::Bot;
+use crate::i18n::{self, Lang};
+use crate::platform::{self, Env};
+use crate::{console_error, console_log};
+
+const KEY_PREFIX: &str = "INDEX_REMINDER:";
+/// Unix time of the soonest reminder, so the scheduled handler only lists the reminders when
+/// one is due.
+const KEY_NEXT_DUE: &str = "INDEX_REMINDER_NEXT_DUE";
+/// Minutes between listings of the reminders even when none is due, in case a concurrent
+/// `add` lost its update of `KEY_NEXT_DUE`.
+const RESCAN_MINUTES: i64 = 60;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// Timezone of chats that didn't set one.
+const VAR_TIMEZONE: &str = "TIMEZONE";
+/// Time of day for reminders given only a day.
+const DEFAULT_TIME: (u32, u32) = (9, 0);
+/// Shortest interval of a recurring reminder, the cron trigger runs every minute.
+const MIN_REPEAT: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reminder {
+    /// Id of the message that created the reminder, unique in the chat.
+    pub id: i64,
+    pub chat_id: i64,
+    pub text: String,
+    /// Unix time the reminder is due.
+    pub due: i64,
+    /// Seconds between deliveries of a recurring reminder.
+    pub repeat: Option<u64>,
+    /// Minutes after midnight in the timezone of the chat it was set at, recurring reminders of
+    /// whole days come back to it after a delivery was moved by daylight saving time.
+    #[serde(default)]
+    pub time: Option<u32>,
+}
+
+/// Minutes after midnight of `due` in `tz`, see `Reminder::time`.
+pub fn time_of_day(due: DateTime<Utc>, tz: Tz) -> u32 {
+    let time = due.with_timezone(&tz).time();
+    time.hour() * 60 + time.minute()
+}
+
+fn chat_prefix(chat_id: i64) -> String {
+    format!("{}{}:", KEY_PREFIX, chat_id)
+}
+
+impl Reminder {
+    fn key(&self) -> String {
+        format!("{}{}", chat_prefix(self.chat_id), self.id)
+    }
+
+    /// The next time a recurring reminder is due after `now`, skipping the deliveries missed
+    /// while the worker wasn't triggered.
+    ///
+    /// Intervals of whole days keep the time of day in `tz` across daylight saving changes.
+    pub fn next_due(&self, tz: Tz, now: i64) -> Option<i64> {
+        let repeat = self.repeat?;
+        let missed = (now - self.due).max(0) as u64 / repeat;
+        let steps = i64::try_from(missed + 1).ok()?;
+        if repeat % SECONDS_PER_DAY != 0 {
+            return steps
+                .checked_mul(repeat as i64)
+                .and_then(|seconds| self.due.checked_add(seconds));
+        }
+        let due = Utc.timestamp_opt(self.due, 0).single()?.with_timezone(&tz);
+        let days = Duration::try_days(steps.checked_mul((repeat / SECONDS_PER_DAY) as i64)?)?;
+        // the time it was set at, not the one of a delivery moved by daylight saving time
+        let time = match self.time {
+            Some(time) => NaiveTime::from_hms_opt(time / 60, time % 60, 0)?,
+            None => due.time(),
+        };
+        let next = due.date_naive().checked_add_signed(days)?.and_time(time);
+        // the time of day doesn't exist when the clocks skip it, an hour later does
+        tz.from_local_datetime(&next)
+            .earliest()
+            .or_else(|| {
+                tz.from_local_datetime(&(next + Duration::hours(1)))
+                    .earliest()
+            })
+            .map(|next| next.timestamp())
+    }
+
+    pub fn due_in(&self, tz: Tz) -> String {
+        match Utc.timestamp_opt(self.due, 0).single() {
+            Some(due) => due.with_timezone(&tz).format("%Y-%m-%d %H:%M").to_string(),
+            None => self.due.to_string(),
+        }
+    }
+}
+
+/// When a reminder is due, parsed from the start of `/remind` arguments.
+#[derive(Debug, PartialEq)]
+pub struct When {
+    pub due: DateTime<Utc>,
+    pub repeat: Option<u64>,
+    pub text: String,
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    text.split_once(char::is_whitespace).unwrap_or((text, ""))
+}
+
+fn parse_time(word: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(word, "%H:%M").ok()
+}
+
+/// Errors are in `lang`.
+pub fn parse_when(text: &str, now: DateTime<Tz>, lang: Lang) -> Result<When, String> {
+    let (word, mut rest) = split_word(text);
+    if !word.eq_ignore_ascii_case("every") {
+        return match parse_due(word, &mut rest, now, lang)? {
+            Some(due) => finish(due, None, rest, now, lang),
+            None => Err(i18n::text(lang, "remind_usage").to_string()),
+        };
+    }
+    let (interval, after) = split_word(rest);
+    let seconds: u64 = match interval.to_ascii_lowercase().as_str() {
+        "hour" => 60 * 60,
+        "day" => 24 * 60 * 60,
+        "week" => 7 * 24 * 60 * 60,
+        interval => parse_duration(interval)
+            .ok_or_else(|| i18n::format(lang, "not_interval", &[interval]))?
+            .as_secs(),
+    };
+    if seconds < MIN_REPEAT {
+        return Err(i18n::text(lang, "repeat_too_often").to_string());
+    }
+    let (word, mut rest) = split_word(after);
+    match parse_due(word, &mut rest, now, lang)? {
+        Some(due) => finish(due, Some(seconds), rest, now, lang),
+        // `every 2h stretch` starts one interval from now
+        None => {
+            let due = i64::try_from(seconds)
+                .ok()
+                .and_then(Duration::try_seconds)
+                .and_then(|interval| now.checked_add_signed(interval))
+                .ok_or_else(|| i18n::format(lang, "not_interval", &[interval]))?;
+            finish(due, Some(seconds), after, now, lang)
+        }
+    }
+}
+
+fn finish(
+    due: DateTime<Tz>,
+    repeat: Option<u64>,
+    text: &str,
+    now: DateTime<Tz>,
+    lang: Lang,
+) -> Result<When, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(i18n::text(lang, "reminder_text_missing").to_string());
+    }
+    if due <= now {
+        return Err(i18n::text(lang, "time_passed").to_string());
+    }
+    Ok(When {
+        due: due.with_timezone(&Utc),
+        repeat,
+        text: text.to_string(),
+    })
+}
+
+// `in 2h`, `tomorrow 9:00`, `friday`, `2024-01-01 18:30` or `18:30`, `None` if `word` isn't a time
+fn parse_due(
+    word: &str,
+    rest: &mut &str,
+    now: DateTime<Tz>,
+    lang: Lang,
+) -> Result<Option<DateTime<Tz>>, String> {
+    let tz = now.timezone();
+    let word = word.to_ascii_lowercase();
+    if word == "in" {
+        let (duration, after) = split_word(rest);
+        let due = parse_duration(duration)
+            .and_then(|duration| Duration::from_std(duration).ok())
+            .and_then(|duration| now.checked_add_signed(duration))
+            .ok_or_else(|| i18n::format(lang, "not_duration", &[duration]))?;
+        *rest = after;
+        return Ok(Some(due));
+    }
+    let today = now.date_naive();
+    let weekday = word.parse::<Weekday>().ok();
+    let day = match (word.as_str(), weekday) {
+        ("today", _) => Some(today),
+        ("tomorrow", _) => today.succ_opt(),
+        (_, Some(weekday)) => {
+            let days =
+                (7 + weekday.num_days_from_monday() - today.weekday().num_days_from_monday()) % 7;
+            Some(today + Duration::days(days.into()))
+        }
+        _ => NaiveDate::parse_from_str(&word, "%Y-%m-%d").ok(),
+    };
+    let time = match day {
+        Some(_) => {
+            let (next, after) = split_word(rest);
+            let time = parse_time(next);
+            if time.is_some() {
+                *rest = after;
+            }
+            time
+        }
+        None => parse_time(&word),
+    };
+    if day.is_none() && time.is_none() {
+        return Ok(None);
+    }
+    let time = time
+        .or(NaiveTime::from_hms_opt(DEFAULT_TIME.0, DEFAULT_TIME.1, 0))
+        .expect("valid default time");
+    let at = |day: NaiveDate| {
+        tz.from_local_datetime(&day.and_time(time))
+            .earliest()
+            .ok_or_else(|| i18n::text(lang, "time_not_in_timezone").to_string())
+    };
+    let mut due = at(day.unwrap_or(today))?;
+    // a bare time or a weekday that passed already means the next one
+    if due <= now {
+        match (day, weekday) {
+            (None, _) => due = at(today + Duration::days(1))?,
+            (Some(day), Some(_)) => due = at(day + Duration::days(7))?,
+            _ => {}
+        }
+    }
+    Ok(Some(due))
+}
+
+/// `90s`, `5m`, `1h30m`, `1d`, the reverse of `parse_duration`.
+pub fn format_duration(seconds: u64) -> String {
+    let mut text = String::new();
+    let mut rest = seconds;
+    for (unit, size) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if rest >= size {
+            text += &format!("{}{}", rest / size, unit);
+            rest %= size;
+        }
+    }
+    match text.is_empty() {
+        true => "0s".to_string(),
+        false => text,
+    }
+}
+
+/// Timezone of the chat, set with `/set_timezone`.
+pub async fn chat_timezone(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<Tz, WorkerError> {
+    timezone(env, bot, m.chat.id.0).await
+}
+
+/// Timezone of the chat `chat_id`, for when there is no message of it.
+pub async fn timezone(env: &Env, bot: &Bot<'_>, chat_id: i64) -> Result<Tz, WorkerError> {
+    let timezone = match bot
+        .store(env)?
+        .get(&format!("INDEX_CHAT_TIMEZONE:{}", chat_id))
+        .text()
+        .await?
+    {
+        Some(timezone) => Some(timezone),
+        None => bot.var(env, VAR_TIMEZONE),
+    };
+    Ok(timezone
+        .and_then(|timezone| timezone.parse::<Tz>().ok())
+        .unwrap_or(Tz::UTC))
+}
+
+pub fn now() -> i64 {
+    (platform::now_millis() / 1000) as i64
+}
+
+pub async fn add(env: &Env, bot: &Bot<'_>, reminder: &Reminder) -> Result<(), WorkerError> {
+    let store = bot.store(env)?;
+    store.put(&reminder.key(), reminder)?.execute().await?;
+    let next_due = store.get(KEY_NEXT_DUE).json::<i64>().await?;
+    if next_due.is_none_or(|next_due| reminder.due < next_due) {
+        store.put(KEY_NEXT_DUE, reminder.due)?.execute().await?;
+    }
+    Ok(())
+}
+
+async fn load(env: &Env, bot: &Bot<'_>, key: &str) -> Result<Option<Reminder>, WorkerError> {
+    bot.store(env)?.get(key).json::<Reminder>().await
+}
+
+/// Reminders of a chat, soonest first.
+pub async fn list(m: &Message, env: &Env, bot: &Bot<'_>) -> Result<Vec<Reminder>, WorkerError> {
+    let mut reminders = vec![];
+    for key in bot.store(env)?.list(&chat_prefix(m.chat.id.0)).await? {
+        if let Some(reminder) = load(env, bot, &key).await? {
+            reminders.push(reminder);
+        }
+    }
+    reminders.sort_by_key(|reminder| reminder.due);
+    Ok(reminders)
+}
+
+/// Remove the reminder `id` of the chat, `false` if there is none.
+pub async fn remove(m: &Message, env: &Env, bot: &Bot<'_>, id: i64) -> Result<bool, WorkerError> {
+    let store = bot.store(env)?;
+    let key = format!("{}{}", chat_prefix(m.chat.id.0), id);
+    if store.get(&key).text().await?.is_none() {
+        return Ok(false);
+    }
+    // `KEY_NEXT_DUE` may now be early, which only costs an extra listing
+    store.delete(&key).await?;
+    Ok(true)
+}
+
+/// Send the reminders that are due and reschedule the recurring ones.
+///
+/// The reminders are only listed when `KEY_NEXT_DUE` says one is due, and once every
+/// `RESCAN_MINUTES`, as KV allows few list operations a day.
+pub async fn deliver_due(env: &Env, bot: &Bot<'_>) -> Result<(), WorkerError> {
+    let now = now();
+    let store = bot.store(env)?;
+    let next_due = store.get(KEY_NEXT_DUE).json::<i64>().await?;
+    let rescan = (now / 60) % RESCAN_MINUTES == 0;
+    if next_due.is_some_and(|next_due| next_due > now) && !rescan {
+        return Ok(());
+    }
+    let mut next_due = None::<i64>;
+    for key in store.list(KEY_PREFIX).await? {
+        let Some(reminder) = load(env, bot, &key).await? else {
+            continue;
+        };
+        if reminder.due > now {
+            next_due = Some(next_due.map_or(reminder.due, |due| due.min(reminder.due)));
+            continue;
+        }
+        console_log!("Delivering reminder {:?}", reminder);
+        let text = format!("⏰ {}", reminder.text);
+        let message = SendMessage::new(ChatTarget::Id(ChatId(reminder.chat_id)), text.as_str());
+        if let Err(err) = bot.send_message(message).await {
+            console_error!("Failed to deliver reminder {}: {}", key, err);
+        }
+        let tz = timezone(env, bot, reminder.chat_id).await?;
+        match reminder.next_due(tz, now) {
+            Some(due) => {
+                let next = Reminder { due, ..reminder };
+                store.put(&next.key(), &next)?.execute().await?;
+                next_due = Some(next_due.map_or(due, |next_due| next_due.min(due)));
+            }
+            None => store.delete(&key).await?,
+        }
+    }
+    match next_due {
+        Some(next_due) => store.put(KEY_NEXT_DUE, next_due)?.execute().await?,
+        None => store.delete(KEY_NEXT_DUE).await?,
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_when() {
+    let tz: Tz = "Asia/Shanghai".parse().unwrap();
+    // Wednesday
+    let now = tz.with_ymd_and_hms(2024, 1, 3, 10, 0, 0).unwrap();
+    let at = |y, mo, d, h, mi| {
+        tz.with_ymd_and_hms(y, mo, d, h, mi, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+    let when = parse_when("in 1h30m check deploy", now, Lang::En).unwrap();
+    assert_eq!(when.due, at(2024, 1, 3, 11, 30));
+    assert_eq!(when.text, "check deploy");
+    assert_eq!(
+        parse_when("tomorrow 9:00 standup", now, Lang::En)
+            .unwrap()
+            .due,
+        at(2024, 1, 4, 9, 0)
+    );
+    assert_eq!(
+        parse_when("8:00 coffee", now, Lang::En).unwrap().due,
+        at(2024, 1, 4, 8, 0)
+    );
+    assert_eq!(
+        parse_when("wednesday lunch", now, Lang::En).unwrap().due,
+        at(2024, 1, 10, 9, 0)
+    );
+    assert_eq!(
+        parse_when("friday 18:30 beer", now, Lang::En).unwrap().due,
+        at(2024, 1, 5, 18, 30)
+    );
+    let when = parse_when("every day 9:00 standup", now, Lang::En).unwrap();
+    assert_eq!((when.due, when.repeat), (at(2024, 1, 4, 9, 0), Some(86400)));
+    let when = parse_when("every 2h stretch", now, Lang::En).unwrap();
+    assert_eq!(
+        (when.due, when.text.as_str()),
+        (at(2024, 1, 3, 12, 0), "stretch")
+    );
+    assert!(parse_when("2023-12-31 party", now, Lang::En).is_err());
+    assert!(parse_when("in 2h", now, Lang::En).is_err());
+    assert!(parse_when("check deploy", now, Lang::En).is_err());
+    assert!(parse_when("in 9000000000000s x", now, Lang::En).is_err());
+    assert_eq!(format_duration(5400), "1h30m");
+}
+
+#[test]
+fn test_next_due() {
+    let tz: Tz = "Europe/Berlin".parse().unwrap();
+    let at = |y, mo, d, h, mi| tz.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap().timestamp();
+    let reminder = |due, repeat| Reminder {
+        id: 1,
+        chat_id: 1,
+        text: "standup".to_string(),
+        due,
+        repeat: Some(repeat),
+        time: Some(time_of_day(Utc.timestamp_opt(due, 0).unwrap(), tz)),
+    };
+    // summer time starts on 2024-03-31
+    let daily = reminder(at(2024, 3, 30, 9, 0), 86400);
+    let now = at(2024, 3, 30, 9, 0);
+    assert_eq!(daily.next_due(tz, now), Some(at(2024, 3, 31, 9, 0)));
+    // missed deliveries are skipped
+    let now = at(2024, 4, 2, 12, 0);
+    assert_eq!(daily.next_due(tz, now), Some(at(2024, 4, 3, 9, 0)));
+    let hourly = reminder(at(2024, 3, 31, 1, 30), 3600);
+    let now = at(2024, 3, 31, 1, 30);
+    assert_eq!(hourly.next_due(tz, now), Some(at(2024, 3, 31, 3, 30)));
+    // 2:30 doesn't exist on 2024-03-31, the day after is at 2:30 again
+    let early = reminder(at(2024, 3, 30, 2, 30), 86400);
+    let skipped = early.next_due(tz, at(2024, 3, 30, 2, 30)).unwrap();
+    assert_eq!(skipped, at(2024, 3, 31, 3, 30));
+    let moved = Reminder {
+        due: skipped,
+        ..early
+    };
+    assert_eq!(moved.next_due(tz, skipped), Some(at(2024, 4, 1, 2, 30)));
+    let once = Reminder {
+        repeat: None,
+        ..daily
+    };
+    assert_eq!(once.next_due(tz, now), None);
+}
@@ -0,0 +1,77 @@
+//! Telegram actions produced by a command handler.
+use serde_json::Value;
+use telegram_types::bot::methods::{
+    ChatTarget, DeleteMessage, EditMessageText, Method, SendMessage,
+};
+use telegram_types::bot::types::{ChatId, MessageId};
+
+use crate::methods::{ReactionType, SetMessageReaction};
+
+/// A single Bot API call.
+pub enum Action {
+    /// A JSON encoded method call, which can be returned as the webhook reply.
+    Call { method: String, payload: Value },
+}
+
+/// Actions to run in response to an update, in order.
+///
+/// The first action goes in the webhook response when it can, the rest are made as Bot API
+/// calls afterwards, in order. Those calls finish before the response is sent, so Telegram
+/// may apply them before the first action.
+#[derive(Default)]
+pub struct Reply {
+    actions: Vec<Action>,
+}
+
+impl Reply {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn call<T: Method>(method: T) -> Self {
+        Self::none().then(method)
+    }
+
+    pub fn then<T: Method>(mut self, method: T) -> Self {
+        self.actions.push(Action::Call {
+            method: T::NAME.to_string(),
+            payload: serde_json::to_value(method).expect("Bot API methods are plain JSON"),
+        });
+        self
+    }
+
+    pub fn send(self, message: SendMessage<'_>) -> Self {
+        self.then(message)
+    }
+
+    pub fn edit<S: AsRef<str>>(self, chat_id: ChatId, message_id: MessageId, text: S) -> Self {
+        self.then(EditMessageText::new(
+            ChatTarget::Id(chat_id),
+            message_id,
+            text.as_ref(),
+        ))
+    }
+
+    pub fn delete(self, chat_id: ChatId, message_id: MessageId) -> Self {
+        self.then(DeleteMessage {
+            chat_id: ChatTarget::Id(chat_id),
+            message_id,
+        })
+    }
+
+    pub fn react<S: AsRef<str>>(self, chat_id: ChatId, message_id: MessageId, emoji: S) -> Self {
+        self.then(SetMessageReaction {
+            chat_id: ChatTarget::Id(chat_id),
+            message_id,
+            reaction: vec![ReactionType::emoji(emoji)],
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    pub fn into_actions(self) -> Vec<Action> {
+        self.actions
+    }
+}
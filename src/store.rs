@@ -0,0 +1,276 @@
+//! KV store of a single bot, keys are namespaced so several bots can share one binding.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::kv::{GetOptionsBuilder, KvStore, PutOptionsBuilder};
+use worker::Error as WorkerError;
+
+use crate::console_error;
+use crate::platform;
+
+/// A KV namespace, of Workers KV or of the local runner.
+#[derive(Clone)]
+pub enum Kv {
+    Worker(KvStore),
+    Local(LocalKv),
+}
+
+/// KV namespace of the local runner, written to a JSON file after every change when it has one.
+#[derive(Clone, Debug, Default)]
+pub struct LocalKv {
+    entries: Rc<RefCell<BTreeMap<String, LocalEntry>>>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LocalEntry {
+    value: String,
+    /// Unix time the entry expires at.
+    expiration: Option<u64>,
+}
+
+impl LocalEntry {
+    fn is_live(&self) -> bool {
+        self.expiration
+            .is_none_or(|expiration| expiration > platform::now_millis() / 1000)
+    }
+}
+
+impl LocalKv {
+    /// Namespace kept in `file`, or only in memory.
+    pub fn open(file: Option<PathBuf>) -> Self {
+        let entries = file
+            .as_ref()
+            .and_then(|file| std::fs::read(file).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            entries: Rc::new(RefCell::new(entries)),
+            file,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        let entries = self.entries.borrow();
+        let entry = entries.get(key).filter(|entry| entry.is_live())?;
+        Some(entry.value.clone())
+    }
+
+    fn put(&self, key: String, entry: LocalEntry) {
+        self.entries.borrow_mut().insert(key, entry);
+        self.save();
+    }
+
+    fn delete(&self, key: &str) {
+        self.entries.borrow_mut().remove(key);
+        self.save();
+    }
+
+    fn list(&self, prefix: &str) -> Vec<String> {
+        self.entries
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| entry.is_live())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn save(&self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let result = serde_json::to_vec_pretty(&*self.entries.borrow())
+            .map_err(|err| err.to_string())
+            .and_then(|data| {
+                if let Some(dir) = file.parent() {
+                    std::fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+                }
+                std::fs::write(file, data).map_err(|err| err.to_string())
+            });
+        if let Err(err) = result {
+            console_error!("Failed to save {}: {}", file.display(), err);
+        }
+    }
+}
+
+pub struct Store {
+    kv: Kv,
+    prefix: String,
+}
+
+/// A pending read, see `Store::get`.
+pub enum Get {
+    Worker(GetOptionsBuilder),
+    Local(Option<String>),
+}
+
+impl Get {
+    pub async fn text(self) -> Result<Option<String>, WorkerError> {
+        match self {
+            Get::Worker(get) => Ok(get.text().await?),
+            Get::Local(value) => Ok(value),
+        }
+    }
+
+    pub async fn json<T: DeserializeOwned>(self) -> Result<Option<T>, WorkerError> {
+        match self {
+            Get::Worker(get) => Ok(get.json().await?),
+            Get::Local(value) => Ok(value
+                .map(|value| serde_json::from_str(&value))
+                .transpose()?),
+        }
+    }
+}
+
+/// A pending write, see `Store::put`.
+#[derive(Debug)]
+pub enum Put {
+    Worker(PutOptionsBuilder),
+    Local {
+        kv: LocalKv,
+        key: String,
+        value: String,
+        expiration_ttl: Option<u64>,
+    },
+}
+
+impl Put {
+    /// Seconds until the key expires, at least 60.
+    pub fn expiration_ttl(self, ttl: u64) -> Self {
+        match self {
+            Put::Worker(put) => Put::Worker(put.expiration_ttl(ttl)),
+            Put::Local { kv, key, value, .. } => Put::Local {
+                kv,
+                key,
+                value,
+                expiration_ttl: Some(ttl),
+            },
+        }
+    }
+
+    pub async fn execute(self) -> Result<(), WorkerError> {
+        match self {
+            Put::Worker(put) => Ok(put.execute().await?),
+            Put::Local {
+                kv,
+                key,
+                value,
+                expiration_ttl,
+            } => {
+                let now = platform::now_millis() / 1000;
+                let expiration = expiration_ttl.map(|ttl| now + ttl);
+                kv.put(key, LocalEntry { value, expiration });
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Store {
+    pub fn new(kv: Kv, prefix: &str) -> Self {
+        Self {
+            kv,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    pub fn get(&self, name: &str) -> Get {
+        match &self.kv {
+            Kv::Worker(kv) => Get::Worker(kv.get(&self.key(name))),
+            Kv::Local(kv) => Get::Local(kv.get(&self.key(name))),
+        }
+    }
+
+    /// Write `value`, strings and numbers as they are and anything else as JSON, like Workers KV.
+    pub fn put<T: Serialize>(&self, name: &str, value: T) -> Result<Put, WorkerError> {
+        match &self.kv {
+            Kv::Worker(kv) => Ok(Put::Worker(kv.put(&self.key(name), value)?)),
+            Kv::Local(kv) => {
+                let value = match serde_json::to_value(value)? {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                Ok(Put::Local {
+                    kv: kv.clone(),
+                    key: self.key(name),
+                    value,
+                    expiration_ttl: None,
+                })
+            }
+        }
+    }
+
+    /// Names of the keys starting with `prefix`, without the namespace of the bot.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<String>, WorkerError> {
+        let kv = match &self.kv {
+            Kv::Worker(kv) => kv,
+            Kv::Local(kv) => {
+                let keys = kv.list(&self.key(prefix));
+                return Ok(keys
+                    .into_iter()
+                    .map(|key| key[self.prefix.len()..].to_string())
+                    .collect());
+            }
+        };
+        let mut names = vec![];
+        let mut cursor = None;
+        loop {
+            let mut list = kv.list().prefix(self.key(prefix));
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+            let response = list.execute().await?;
+            names.extend(
+                response
+                    .keys
+                    .into_iter()
+                    .map(|key| key.name[self.prefix.len()..].to_string()),
+            );
+            if response.list_complete {
+                return Ok(names);
+            }
+            cursor = response.cursor;
+        }
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), WorkerError> {
+        match &self.kv {
+            Kv::Worker(kv) => Ok(kv.delete(&self.key(name)).await?),
+            Kv::Local(kv) => {
+                kv.delete(&self.key(name));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_local_store() {
+    let store = Store::new(Kv::Local(LocalKv::open(None)), "test:");
+    let get = |name: &str| futures::executor::block_on(store.get(name).text()).unwrap();
+    futures::executor::block_on(async {
+        store.put("a:1", "one").unwrap().execute().await.unwrap();
+        store.put("a:2", 2).unwrap().execute().await.unwrap();
+        store
+            .put("b:1", vec![1, 2])
+            .unwrap()
+            .execute()
+            .await
+            .unwrap();
+        assert_eq!(store.list("a:").await.unwrap(), vec!["a:1", "a:2"]);
+        store.delete("a:1").await.unwrap();
+    });
+    assert_eq!(get("a:1"), None);
+    assert_eq!(get("a:2").as_deref(), Some("2"));
+    assert_eq!(get("b:1").as_deref(), Some("[1,2]"));
+}
@@ -0,0 +1,209 @@
+//! Titles of groups rendered from a template of segments and kept up to date by the scheduled
+//! handler.
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use worker::Error as WorkerError;
+
+use crate::bot::Bot;
+use crate::methods::SetChatTitle;
+use crate::platform::Env;
+use crate::reminder;
+use crate::{console_error, console_log};
+
+const KEY_PREFIX: &str = "INDEX_TITLE:";
+const DEFAULT_DELIMITER: &str = " ";
+/// Longest title Telegram accepts.
+const MAX_TITLE_LENGTH: usize = 128;
+/// Minutes between title updates, as every new title posts a service message in the group.
+const VAR_UPDATE_MINUTES: &str = "TITLE_UPDATE_MINUTES";
+const DEFAULT_UPDATE_MINUTES: i64 = 15;
+
+/// Title template of a group, changed with `/set_template`, `/push` and friends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TitleConfig {
+    pub enabled: bool,
+    pub segments: Vec<String>,
+    pub delimiter: String,
+    /// Title set last, so it is only set again when it changes.
+    pub last_title: Option<String>,
+}
+
+impl Default for TitleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            segments: vec![],
+            delimiter: DEFAULT_DELIMITER.to_string(),
+            last_title: None,
+        }
+    }
+}
+
+impl TitleConfig {
+    /// Replace the segments with the ones of `template`, split at the delimiter.
+    pub fn set_template(&mut self, template: &str) {
+        self.segments = template
+            .split(self.delimiter.as_str())
+            .filter(|segment| !segment.is_empty())
+            .map(str::to_string)
+            .collect();
+    }
+
+    /// The title at `now`, placeholders like `{date}` or `{%H:%M}` filled in.
+    pub fn render(&self, now: DateTime<Tz>) -> String {
+        let title = self
+            .segments
+            .iter()
+            .map(|segment| render_segment(segment, now))
+            .collect::<Vec<String>>()
+            .join(&self.delimiter);
+        title.trim().chars().take(MAX_TITLE_LENGTH).collect()
+    }
+}
+
+// unknown placeholders are kept as they are
+fn render_segment(segment: &str, now: DateTime<Tz>) -> String {
+    let mut text = String::new();
+    let mut rest = segment;
+    while let Some(start) = rest.find('{') {
+        text += &rest[..start];
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('}') else {
+            text += &rest[start..];
+            return text;
+        };
+        let name = &after[..end];
+        match placeholder(name, now) {
+            Some(value) => text += &value,
+            None => text += &rest[start..start + end + 2],
+        }
+        rest = &after[end + 1..];
+    }
+    text + rest
+}
+
+fn placeholder(name: &str, now: DateTime<Tz>) -> Option<String> {
+    let format = match name {
+        "date" => "%Y-%m-%d",
+        "time" => "%H:%M",
+        "weekday" => "%A",
+        "month" => "%B",
+        "year" => "%Y",
+        format if format.starts_with('%') => format,
+        _ => return None,
+    };
+    let items = StrftimeItems::new(format).collect::<Vec<Item>>();
+    // formatting an invalid specifier panics
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return None;
+    }
+    Some(now.format_with_items(items.into_iter()).to_string())
+}
+
+fn key(chat_id: i64) -> String {
+    format!("{}{}", KEY_PREFIX, chat_id)
+}
+
+pub async fn load(env: &Env, bot: &Bot<'_>, chat_id: i64) -> Result<TitleConfig, WorkerError> {
+    let config = bot.store(env)?.get(&key(chat_id)).json().await?;
+    Ok(config.unwrap_or_default())
+}
+
+pub async fn save(
+    env: &Env,
+    bot: &Bot<'_>,
+    chat_id: i64,
+    config: &TitleConfig,
+) -> Result<(), WorkerError> {
+    bot.store(env)?
+        .put(&key(chat_id), config)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub fn now_in(tz: Tz) -> DateTime<Tz> {
+    Utc.timestamp_opt(reminder::now(), 0)
+        .single()
+        .unwrap_or_default()
+        .with_timezone(&tz)
+}
+
+fn update_minutes(env: &Env, bot: &Bot<'_>) -> i64 {
+    bot.var(env, VAR_UPDATE_MINUTES)
+        .and_then(|minutes| minutes.parse::<i64>().ok())
+        .filter(|minutes| *minutes > 0)
+        .unwrap_or(DEFAULT_UPDATE_MINUTES)
+}
+
+/// Set the titles of the enabled groups whose rendered title changed, every
+/// `TITLE_UPDATE_MINUTES`, so `{time}` moves in steps of that many minutes.
+pub async fn update_titles(env: &Env, bot: &Bot<'_>) -> Result<(), WorkerError> {
+    if (reminder::now() / 60) % update_minutes(env, bot) != 0 {
+        return Ok(());
+    }
+    for key in bot.store(env)?.list(KEY_PREFIX).await? {
+        let Some(chat_id) = key
+            .strip_prefix(KEY_PREFIX)
+            .and_then(|chat_id| chat_id.parse::<i64>().ok())
+        else {
+            continue;
+        };
+        // one broken entry doesn't stop the titles of the other groups
+        let mut config = match load(env, bot, chat_id).await {
+            Ok(config) => config,
+            Err(err) => {
+                console_error!("Failed to load the title of {}: {}", chat_id, err);
+                continue;
+            }
+        };
+        if !config.enabled {
+            continue;
+        }
+        let tz = match reminder::timezone(env, bot, chat_id).await {
+            Ok(tz) => tz,
+            Err(err) => {
+                console_error!("Failed to load the timezone of {}: {}", chat_id, err);
+                continue;
+            }
+        };
+        let title = config.render(now_in(tz));
+        if title.is_empty() || config.last_title.as_ref() == Some(&title) {
+            continue;
+        }
+        console_log!("Setting the title of {} to {:?}", chat_id, title);
+        let set_title = SetChatTitle {
+            chat_id,
+            title: title.clone(),
+        };
+        if let Err(err) = bot.call_method(set_title).await {
+            console_error!("Failed to set the title of {}: {}", chat_id, err);
+            continue;
+        }
+        config.last_title = Some(title);
+        if let Err(err) = save(env, bot, chat_id, &config).await {
+            console_error!("Failed to save the title of {}: {}", chat_id, err);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_render() {
+    let tz: Tz = "Asia/Shanghai".parse().unwrap();
+    let now = tz.with_ymd_and_hms(2024, 1, 3, 9, 5, 0).unwrap();
+    let mut config = TitleConfig {
+        delimiter: " | ".to_string(),
+        ..TitleConfig::default()
+    };
+    config.set_template("Team | {weekday} {date} | {%H:%M} | {unknown} {%Q} | {open");
+    assert_eq!(config.segments.len(), 5);
+    assert_eq!(
+        config.render(now),
+        "Team | Wednesday 2024-01-03 | 09:05 | {unknown} {%Q} | {open"
+    );
+    config.segments.push("x".repeat(200));
+    assert_eq!(config.render(now).chars().count(), MAX_TITLE_LENGTH);
+}
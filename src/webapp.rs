@@ -0,0 +1,380 @@
+//! Mini App for the chat settings, served by the worker next to the webhook.
+//!
+//! The app is opened from the menu button, every API request carries the `initData` Telegram
+//! signed with the bot token, see <https://core.telegram.org/bots/webapps#validating-data-received-via-the-mini-app>.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use telegram_types::bot::methods::ChatTarget;
+use telegram_types::bot::types::{ChatId, UserId};
+use worker::{Error as WorkerError, Request, Response, RouteContext};
+
+use crate::bot::Bot;
+use crate::console_log;
+use crate::i18n::{self, Lang};
+use crate::methods::{MenuButton, SetChatMenuButton, WebAppInfo};
+use crate::middleware::is_allowed;
+use crate::platform::Env;
+use crate::reminder;
+
+pub const APP_HTML: &str = include_str!("webapp.html");
+/// Catalog keys of the texts of the app.
+const APP_TEXTS: &[&str] = &[
+    "app_title",
+    "app_chat",
+    "app_system_prompt",
+    "app_model",
+    "app_model_default",
+    "app_endpoint",
+    "app_api_key",
+    "app_keep_key",
+    "app_bot_key",
+    "app_remove_key",
+    "app_history_length",
+    "app_save",
+];
+/// Header the app sends `Telegram.WebApp.initData` in.
+const HEADER_INIT_DATA: &str = "X-Telegram-Init-Data";
+/// Seconds the app can be used after it was opened.
+const INIT_DATA_TTL: i64 = 24 * 60 * 60;
+/// URL of the app, remembered when the webhook is set up.
+const KEY_APP_URL: &str = "APP_URL";
+const MENU_BUTTON_TEXT: &str = "Settings";
+/// Most earlier messages a chat may send to the model.
+const MAX_HISTORY_LENGTH: usize = 50;
+
+/// The user who opened the app.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WebAppUser {
+    pub id: i64,
+    pub first_name: String,
+}
+
+/// A chat the settings of which a user may edit.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SettingsChat {
+    pub id: i64,
+    pub title: String,
+}
+
+/// What the app edits, unset fields use the defaults of the bot.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChatSettings {
+    pub system_prompt: Option<String>,
+    pub model: Option<String>,
+    /// OpenAI compatible chat completions endpoint.
+    pub endpoint: Option<String>,
+    /// Only ever sent by the app, empty to remove the key.
+    #[serde(default, skip_serializing)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub has_api_key: bool,
+    /// Earlier messages sent along with a new one.
+    pub history_length: Option<usize>,
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    match key.len() > block.len() {
+        true => block[..32].copy_from_slice(&Sha256::digest(key)),
+        false => block[..key.len()].copy_from_slice(key),
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Check the signature and age of `initData`, returning the user it was issued to.
+pub fn validate_init_data(init_data: &str, token: &str, now: i64) -> Result<WebAppUser, String> {
+    let mut fields = form_urlencoded::parse(init_data.as_bytes())
+        .into_owned()
+        .collect::<BTreeMap<String, String>>();
+    let hash = fields.remove("hash").ok_or("Missing hash")?;
+    let data_check_string = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let secret_key = hmac_sha256(b"WebAppData", token.as_bytes());
+    let expected = hex(&hmac_sha256(&secret_key, data_check_string.as_bytes()));
+    // compare without stopping at the first difference
+    let matches = expected.len() == hash.len()
+        && expected
+            .bytes()
+            .zip(hash.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return Err("Invalid hash".to_string());
+    }
+    let auth_date = fields
+        .get("auth_date")
+        .and_then(|date| date.parse::<i64>().ok())
+        .ok_or("Missing auth_date")?;
+    if now - auth_date > INIT_DATA_TTL {
+        return Err("Expired, reopen the app".to_string());
+    }
+    let user = fields.get("user").ok_or("Missing user")?;
+    serde_json::from_str(user).map_err(|err| err.to_string())
+}
+
+/// Remember where the app is served and open it from the menu button.
+pub async fn setup(bot: &Bot<'_>, env: &Env, url: String) -> Result<(), WorkerError> {
+    bot.store(env)?
+        .put(KEY_APP_URL, url.as_str())?
+        .execute()
+        .await?;
+    bot.call_method(SetChatMenuButton {
+        chat_id: None,
+        menu_button: menu_button(Some(url)),
+    })
+    .await?;
+    Ok(())
+}
+
+pub fn menu_button(url: Option<String>) -> MenuButton {
+    match url {
+        Some(url) => MenuButton::WebApp {
+            text: MENU_BUTTON_TEXT.to_string(),
+            web_app: WebAppInfo { url },
+        },
+        None => MenuButton::Commands,
+    }
+}
+
+pub async fn app_url(bot: &Bot<'_>, env: &Env) -> Result<Option<String>, WorkerError> {
+    bot.store(env)?.get(KEY_APP_URL).text().await
+}
+
+fn chats_key(user_id: i64) -> String {
+    format!("INDEX_SETTINGS_CHATS:{}", user_id)
+}
+
+async fn registered_chats(
+    bot: &Bot<'_>,
+    env: &Env,
+    user_id: i64,
+) -> Result<Vec<SettingsChat>, WorkerError> {
+    let chats = bot.store(env)?.get(&chats_key(user_id)).json().await?;
+    Ok(chats.unwrap_or_default())
+}
+
+/// List a group in the app of an administrator, done by `/settings` in the group.
+pub async fn register_chat(
+    bot: &Bot<'_>,
+    env: &Env,
+    user_id: i64,
+    chat: SettingsChat,
+) -> Result<(), WorkerError> {
+    let mut chats = registered_chats(bot, env, user_id).await?;
+    chats.retain(|known| known.id != chat.id);
+    chats.push(chat);
+    bot.store(env)?
+        .put(&chats_key(user_id), chats)?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+// the private chat with the user, or a group they registered and still administer
+async fn can_edit(
+    bot: &Bot<'_>,
+    env: &Env,
+    user: &WebAppUser,
+    chat_id: i64,
+) -> Result<bool, WorkerError> {
+    if !is_allowed(bot, env, chat_id) {
+        return Ok(false);
+    }
+    if chat_id == user.id {
+        return Ok(true);
+    }
+    let registered = registered_chats(bot, env, user.id)
+        .await?
+        .iter()
+        .any(|chat| chat.id == chat_id);
+    Ok(registered
+        && bot
+            .is_admin(ChatTarget::Id(ChatId(chat_id)), UserId(user.id))
+            .await?)
+}
+
+fn user(req: &Request, bot: &Bot<'_>) -> Result<Option<WebAppUser>, WorkerError> {
+    let Some(init_data) = req.headers().get(HEADER_INIT_DATA)? else {
+        return Ok(None);
+    };
+    match validate_init_data(&init_data, &bot.token, reminder::now()) {
+        Ok(user) => Ok(Some(user)),
+        Err(err) => {
+            console_log!("Rejected app request: {}", err);
+            Ok(None)
+        }
+    }
+}
+
+/// The app with its texts in every language, the app picks the one of the user or else `lang`.
+pub fn render(lang: Lang) -> String {
+    let texts = Lang::ALL
+        .iter()
+        .map(|&lang| {
+            let texts = APP_TEXTS
+                .iter()
+                .map(|&key| (key, i18n::text(lang, key)))
+                .collect::<BTreeMap<_, _>>();
+            (lang.code(), texts)
+        })
+        .collect::<BTreeMap<_, _>>();
+    APP_HTML
+        .replace(
+            "{{texts}}",
+            &serde_json::to_string(&texts).unwrap_or_default(),
+        )
+        .replace("{{lang}}", lang.code())
+}
+
+pub async fn page(_req: Request, ctx: RouteContext<Bot<'_>>) -> Result<Response, WorkerError> {
+    Response::from_html(render(ctx.data.lang))
+}
+
+/// `GET /{route}/app/chats`
+pub async fn chats(req: Request, ctx: RouteContext<Bot<'_>>) -> Result<Response, WorkerError> {
+    let (bot, env) = (&ctx.data, &Env::from(&ctx.env));
+    let Some(user) = user(&req, bot)? else {
+        return Response::error("Unauthorized", 401);
+    };
+    let mut chats = vec![];
+    let private = SettingsChat {
+        id: user.id,
+        title: user.first_name.clone(),
+    };
+    for chat in std::iter::once(private).chain(registered_chats(bot, env, user.id).await?) {
+        if can_edit(bot, env, &user, chat.id).await? {
+            chats.push(chat);
+        }
+    }
+    Response::from_json(&chats)
+}
+
+fn chat_id(ctx: &RouteContext<Bot<'_>>) -> Option<i64> {
+    ctx.param("chat_id").and_then(|id| id.parse().ok())
+}
+
+/// `GET /{route}/app/settings/:chat_id`
+pub async fn get_settings(
+    req: Request,
+    ctx: RouteContext<Bot<'_>>,
+) -> Result<Response, WorkerError> {
+    let (bot, env) = (&ctx.data, &Env::from(&ctx.env));
+    let (Some(user), Some(chat_id)) = (user(&req, bot)?, chat_id(&ctx)) else {
+        return Response::error("Unauthorized", 401);
+    };
+    if !can_edit(bot, env, &user, chat_id).await? {
+        return Response::error("Forbidden", 403);
+    }
+    let store = bot.store(env)?;
+    let text = |prefix: &str| store.get(&format!("{}:{}", prefix, chat_id)).text();
+    let settings = ChatSettings {
+        system_prompt: text("INDEX_CHAT_ENV").await?,
+        model: text("INDEX_CHAT_MODEL").await?,
+        endpoint: text("USER_OPENAI_ENDPOINT").await?,
+        api_key: None,
+        has_api_key: text("USER_OPENAI_KEY").await?.is_some(),
+        history_length: text("INDEX_CHAT_HISTORY_LENGTH")
+            .await?
+            .and_then(|length| length.parse().ok()),
+    };
+    Response::from_json(&settings)
+}
+
+/// `POST /{route}/app/settings/:chat_id`
+pub async fn put_settings(
+    mut req: Request,
+    ctx: RouteContext<Bot<'_>>,
+) -> Result<Response, WorkerError> {
+    let (bot, env) = (&ctx.data, &Env::from(&ctx.env));
+    let (Some(user), Some(chat_id)) = (user(&req, bot)?, chat_id(&ctx)) else {
+        return Response::error("Unauthorized", 401);
+    };
+    if !can_edit(bot, env, &user, chat_id).await? {
+        return Response::error("Forbidden", 403);
+    }
+    let settings = req.json::<ChatSettings>().await?;
+    if let Some(endpoint) = settings.endpoint.as_deref().filter(|url| !url.is_empty()) {
+        if worker::Url::parse(endpoint).is_err() {
+            return Response::error("The endpoint is not a URL", 400);
+        }
+    }
+    let store = bot.store(env)?;
+    let history_length = settings
+        .history_length
+        .map(|length| length.min(MAX_HISTORY_LENGTH).to_string());
+    let values = [
+        ("INDEX_CHAT_ENV", settings.system_prompt),
+        ("INDEX_CHAT_MODEL", settings.model),
+        ("USER_OPENAI_ENDPOINT", settings.endpoint),
+        ("INDEX_CHAT_HISTORY_LENGTH", history_length),
+    ];
+    for (prefix, value) in values {
+        let key = format!("{}:{}", prefix, chat_id);
+        match value.filter(|value| !value.trim().is_empty()) {
+            Some(value) => store.put(&key, value)?.execute().await?,
+            None => store.delete(&key).await?,
+        }
+    }
+    // the key is kept unless the app sends a new or an empty one
+    if let Some(api_key) = settings.api_key {
+        let key = format!("USER_OPENAI_KEY:{}", chat_id);
+        match api_key.trim().is_empty() {
+            true => store.delete(&key).await?,
+            false => store.put(&key, api_key.trim())?.execute().await?,
+        }
+    }
+    console_log!("User {} updated the settings of chat {}", user.id, chat_id);
+    Response::from_json(&serde_json::json!({ "ok": true }))
+}
+
+#[test]
+fn test_validate_init_data() {
+    assert_eq!(
+        hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    let token = "123456:TEST";
+    let user = r#"{"id":374506773,"first_name":"Kevin"}"#;
+    let data_check_string = format!("auth_date=1700000000\nquery_id=AAE\nuser={}", user);
+    let secret_key = hmac_sha256(b"WebAppData", token.as_bytes());
+    let hash = hex(&hmac_sha256(&secret_key, data_check_string.as_bytes()));
+    let init_data = form_urlencoded::Serializer::new(String::new())
+        .append_pair("query_id", "AAE")
+        .append_pair("user", user)
+        .append_pair("auth_date", "1700000000")
+        .append_pair("hash", &hash)
+        .finish();
+    let user = validate_init_data(&init_data, token, 1700000060).unwrap();
+    assert_eq!(user.id, 374506773);
+    assert!(validate_init_data(&init_data, "654321:TEST", 1700000060).is_err());
+    assert!(validate_init_data(&init_data, token, 1800000000).is_err());
+    let tampered = init_data.replace("374506773", "1");
+    assert!(validate_init_data(&tampered, token, 1700000060).is_err());
+}
+
+#[test]
+fn test_render() {
+    let html = render(Lang::Zh);
+    assert!(!html.contains("{{"));
+    assert!(html.contains(r#""app_save":"Save""#));
+    assert!(html.contains(r#""app_save":"保存""#));
+    assert!(html.contains(r#"TEXTS["zh"]"#));
+}